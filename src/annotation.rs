@@ -0,0 +1,167 @@
+use std::fmt;
+use crate::iteration::*;
+use crate::grammar::is_identifier_char;
+#[cfg(feature = "serde")]
+use serde_derive::*;
+
+/// Prefixes of the built-in module annotations.
+const BUILTIN_PREFIXES: [(&str, ModuleAnnotation); 5] = [
+	("~", ModuleAnnotation::CreatePatch),
+	("@O", ModuleAnnotation::Sphere),
+	("@c", ModuleAnnotation::Circle),
+	("@I", ModuleAnnotation::Instance),
+	("@L", ModuleAnnotation::Label)
+];
+
+/// Start of the generic notation `@{id}` of application defined annotations, which is understood regardless of the
+/// registered prefixes.
+const GENERIC_PREFIX: &str = "@{";
+
+/// Characters that are part of the rule and mapping syntax, such as `<` in `A < B -> C`, and therefore can not be used
+/// in prefixes.
+const RULE_SYNTAX_CHARS: &str = "<>:-=";
+
+/// Errors that can occur while registering an annotation prefix.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AnnotationError {
+	/// The prefix is empty, starts with a character that can be a module identifier, contains whitespace,
+	/// parentheses, commas or characters of the rule syntax, or starts like the generic notation `@{id}`.
+	InvalidPrefix(String),
+	/// The prefix is already used by a built-in annotation or an annotation with a different id.
+	PrefixInUse(String)
+}
+
+impl fmt::Display for AnnotationError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			AnnotationError::InvalidPrefix(p) => write!(f, "'{}' is not a valid annotation prefix", p),
+			AnnotationError::PrefixInUse(p) => write!(f, "annotation prefix '{}' is already in use", p)
+		}
+	}
+}
+
+impl std::error::Error for AnnotationError {}
+
+/// The set of annotation prefixes known when parsing module strings, rules and mappings. Besides the built-in
+/// annotations, applications can register prefixes for their own annotations, which are parsed as
+/// `ModuleAnnotation::Custom`. Application defined annotations can always be written as `@{id}`, e.g. `@{3}A`,
+/// which is also how they are displayed unless the registry is passed to `ModuleString::display_with`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct AnnotationRegistry {
+	/// Prefixes of application defined annotations, together with their ids
+	custom: Vec<(u32, String)>
+}
+
+impl AnnotationRegistry {
+	/// Create a registry that only knows the built-in annotations.
+	pub fn new() -> AnnotationRegistry {
+		AnnotationRegistry::default()
+	}
+
+	/// Register an application defined annotation with given prefix, which is parsed as `ModuleAnnotation::Custom(id)`.
+	/// Registering an id again replaces its prefix. Prefixes may not start with a character that can be a module
+	/// identifier, since module strings like "+A" would become ambiguous, and may not contain characters of the rule
+	/// syntax (`<`, `>`, `:`, `-` and `=`), since rules like "A<B -> C" would become ambiguous. If multiple prefixes
+	/// match, the longest one is used.
+	pub fn register(&mut self, id: u32, prefix: &str) -> Result<(), AnnotationError> {
+		let valid = match prefix.chars().next() {
+			Some(c) => !is_identifier_char(c)
+				&& !prefix.starts_with(GENERIC_PREFIX)
+				&& !prefix.chars().any(|c| c.is_whitespace() || "(),".contains(c) || RULE_SYNTAX_CHARS.contains(c)),
+			None => false
+		};
+
+		if(!valid) {
+			return Err(AnnotationError::InvalidPrefix(prefix.to_string()));
+		}
+
+		if(BUILTIN_PREFIXES.iter().any(|(p, _)| *p == prefix) || self.custom.iter().any(|(i, p)| *i != id && p == prefix)) {
+			return Err(AnnotationError::PrefixInUse(prefix.to_string()));
+		}
+
+		self.custom.retain(|(i, _)| *i != id);
+		self.custom.push((id, prefix.to_string()));
+		Ok(())
+	}
+
+	/// Remove the application defined annotation with given id. Returns whether it was registered.
+	pub fn unregister(&mut self, id: u32) -> bool {
+		let length = self.custom.len();
+
+		self.custom.retain(|(i, _)| *i != id);
+		self.custom.len() != length
+	}
+
+	/// Retrieve the prefix of given annotation, if it is known.
+	pub fn prefix(&self, annotation: ModuleAnnotation) -> Option<&str> {
+		match annotation {
+			ModuleAnnotation::Custom(id) => self.custom.iter()
+				.find(|(i, _)| *i == id)
+				.map(|(_, p)| p.as_str()),
+			_ => BUILTIN_PREFIXES.iter()
+				.find(|(_, a)| *a == annotation)
+				.map(|(p, _)| *p)
+		}
+	}
+
+	/// Match an annotation at the start of given text, preferring the longest matching prefix. Returns the
+	/// annotation and the length of its prefix.
+	pub(crate) fn match_annotation(&self, text: &str) -> Option<(ModuleAnnotation, usize)> {
+		if let Some(generic) = Self::match_generic(text) {
+			return Some(generic);
+		}
+
+		let builtin = BUILTIN_PREFIXES.iter()
+			.filter(|(p, _)| text.starts_with(p))
+			.map(|(p, a)| (*a, p.len()));
+
+		let custom = self.custom.iter()
+			.filter(|(_, p)| text.starts_with(p.as_str()))
+			.map(|(id, p)| (ModuleAnnotation::Custom(*id), p.len()));
+
+		builtin.chain(custom).max_by_key(|(_, length)| *length)
+	}
+
+	/// Match the generic notation `@{id}` at the start of given text.
+	fn match_generic(text: &str) -> Option<(ModuleAnnotation, usize)> {
+		let rest = text.strip_prefix(GENERIC_PREFIX)?;
+		let end = rest.find('}')?;
+		let id = rest[..end].parse().ok()?;
+
+		Some((ModuleAnnotation::Custom(id), GENERIC_PREFIX.len() + end + 1))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::grammar::lsystem_parser;
+	use crate::module_string::*;
+
+	#[test]
+	fn display_with_registry_round_trips() {
+		let mut annotations = AnnotationRegistry::new();
+		annotations.register(0, "%").unwrap();
+
+		let modules = ModuleString::from(lsystem_parser::module_string("%A(1)@OB@{1}C", &annotations).unwrap());
+		let text = modules.display_with(&annotations).to_string();
+
+		assert_eq!(text, "%A(1)@OB@{1}C");
+		assert_eq!(modules.to_string(), "@{0}A(1)@OB@{1}C");
+		assert_eq!(ModuleString::from(lsystem_parser::module_string(&text, &annotations).unwrap()), modules);
+		assert_eq!(modules.get(0).unwrap().to_module().display_with(&annotations).to_string(), "%A(1)");
+	}
+
+	#[test]
+	fn rejects_invalid_prefixes() {
+		let mut annotations = AnnotationRegistry::new();
+
+		for prefix in &["", "A", "+", "@{", "% ", "%(", "<", ">", "%:", "-", "="] {
+			assert_eq!(annotations.register(0, prefix), Err(AnnotationError::InvalidPrefix(prefix.to_string())));
+		}
+
+		annotations.register(0, "%").unwrap();
+		assert_eq!(annotations.register(1, "%"), Err(AnnotationError::PrefixInUse("%".to_string())));
+	}
+}
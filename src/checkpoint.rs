@@ -0,0 +1,410 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+use crate::iteration::*;
+use crate::module_string::*;
+
+/// Magic bytes every checkpoint starts with.
+const CHECKPOINT_MAGIC: &[u8; 8] = b"LSYSCKPT";
+
+/// Version of the checkpoint format written by this library. Checkpoints with any other version are rejected.
+pub const CHECKPOINT_VERSION: u32 = 1;
+
+/// Annotation code of custom annotations, which is followed by the annotation id.
+const CUSTOM_ANNOTATION_CODE: u32 = 1;
+
+/// Upper bound for memory reserved up front based on counts read from a checkpoint, which protects against
+/// huge allocations caused by corrupted input.
+const MAX_RESERVE: usize = 1 << 20;
+
+/// Errors that can occur while restoring an iteration engine from a checkpoint.
+#[derive(Debug)]
+pub enum CheckpointError {
+	/// Reading the checkpoint failed, for example because it was truncated.
+	Io(io::Error),
+	/// The data does not start with the checkpoint magic bytes.
+	InvalidHeader,
+	/// The checkpoint was written using an unsupported format version.
+	UnsupportedVersion(u32),
+	/// The checkpoint is malformed.
+	InvalidData(&'static str),
+	/// The checkpoint was created with a rule set that differs from the one of the engine it is restored into.
+	RuleSetMismatch
+}
+
+impl fmt::Display for CheckpointError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			CheckpointError::Io(e) => write!(f, "failed to read checkpoint: {}", e),
+			CheckpointError::InvalidHeader => write!(f, "data is not an iteration checkpoint"),
+			CheckpointError::UnsupportedVersion(v) => write!(f, "unsupported checkpoint version {} (expected {})", v, CHECKPOINT_VERSION),
+			CheckpointError::InvalidData(reason) => write!(f, "malformed checkpoint: {}", reason),
+			CheckpointError::RuleSetMismatch => write!(f, "checkpoint was created with a different rule set")
+		}
+	}
+}
+
+impl std::error::Error for CheckpointError {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match self {
+			CheckpointError::Io(e) => Some(e),
+			_ => None
+		}
+	}
+}
+
+impl From<io::Error> for CheckpointError {
+	fn from(e: io::Error) -> CheckpointError {
+		CheckpointError::Io(e)
+	}
+}
+
+/// Derivation state read from a checkpoint.
+pub(crate) struct Checkpoint {
+	pub rng_key: RngKey,
+	pub generation: u32,
+	pub axiom: ModuleString,
+	pub module_string: ModuleString,
+	/// All generations preceding the current one, if history was enabled
+	pub history: Option<Vec<ModuleString>>
+}
+
+/// 64 bit FNV-1a hash of given bytes.
+fn fnv1a<I: IntoIterator<Item = u8>>(bytes: I) -> u64 {
+	let mut hash: u64 = 0xcbf29ce484222325;
+
+	for byte in bytes {
+		hash ^= byte as u64;
+		hash = hash.wrapping_mul(0x100000001b3);
+	}
+
+	hash
+}
+
+/// Compute a fingerprint of given rule set, used to detect checkpoints being restored into an engine with
+/// different rules. This is the 64 bit FNV-1a hash of a canonical binary encoding of all rules, which does not
+/// depend on how rules are displayed.
+fn fingerprint(rules: &[Rule]) -> u64 {
+	let mut encoding = Vec::new();
+
+	for rule in rules {
+		write_rule(&mut encoding, rule).expect("writing to memory cannot fail");
+	}
+
+	fnv1a(encoding)
+}
+
+fn write_rule<W: Write>(writer: &mut W, rule: &Rule) -> io::Result<()> {
+	for signature in &[rule.pattern.match_left.as_ref(), Some(&rule.pattern.match_center), rule.pattern.match_right.as_ref()] {
+		match signature {
+			None => writer.write_all(&[0])?,
+			Some(signature) => {
+				writer.write_all(&[1])?;
+				writer.write_all(&(signature.identifier as u32).to_le_bytes())?;
+				write_annotation(writer, signature.annotation)?;
+				writer.write_all(&(signature.parameters.len() as u32).to_le_bytes())?;
+
+				for parameter in &signature.parameters {
+					writer.write_all(&(*parameter as u32).to_le_bytes())?;
+				}
+			}
+		}
+	}
+
+	write_boolean(writer, &rule.pattern.condition)?;
+	writer.write_all(&rule.probability.to_le_bytes())?;
+	writer.write_all(&(rule.right_side.len() as u32).to_le_bytes())?;
+
+	for template in &rule.right_side {
+		writer.write_all(&(template.identifier as u32).to_le_bytes())?;
+		write_annotation(writer, template.annotation)?;
+		writer.write_all(&(template.parameter_expressions.len() as u32).to_le_bytes())?;
+
+		for expression in &template.parameter_expressions {
+			write_arithmetic(writer, expression)?;
+		}
+	}
+
+	Ok(())
+}
+
+/// Write given expression in prefix order, with each node identified by a tag byte.
+fn write_arithmetic<W: Write>(writer: &mut W, expression: &ArithmeticExpression) -> io::Result<()> {
+	let (tag, operands): (u8, &[&ArithmeticExpression]) = match expression {
+		ArithmeticExpression::Add(l, r) => (0, &[l, r]),
+		ArithmeticExpression::Sub(l, r) => (1, &[l, r]),
+		ArithmeticExpression::Mul(l, r) => (2, &[l, r]),
+		ArithmeticExpression::Div(l, r) => (3, &[l, r]),
+		ArithmeticExpression::Pow(l, r) => (4, &[l, r]),
+		ArithmeticExpression::Neg(e) => (5, &[e]),
+		ArithmeticExpression::Const(x) => {
+			writer.write_all(&[6])?;
+			return writer.write_all(&x.to_le_bytes());
+		},
+		ArithmeticExpression::Param(p) => {
+			writer.write_all(&[7])?;
+			return writer.write_all(&(*p as u32).to_le_bytes());
+		},
+		ArithmeticExpression::Variable(v) => return writer.write_all(&[8, *v as u8])
+	};
+
+	writer.write_all(&[tag])?;
+
+	for operand in operands {
+		write_arithmetic(writer, operand)?;
+	}
+
+	Ok(())
+}
+
+fn write_boolean<W: Write>(writer: &mut W, expression: &BooleanExpression) -> io::Result<()> {
+	match expression {
+		BooleanExpression::Not(e) => {
+			writer.write_all(&[0])?;
+			write_boolean(writer, e)
+		},
+		BooleanExpression::And(l, r) => {
+			writer.write_all(&[1])?;
+			write_boolean(writer, l)?;
+			write_boolean(writer, r)
+		},
+		BooleanExpression::Or(l, r) => {
+			writer.write_all(&[2])?;
+			write_boolean(writer, l)?;
+			write_boolean(writer, r)
+		},
+		BooleanExpression::Lth(l, r) => write_comparison(writer, 3, l, r),
+		BooleanExpression::Leq(l, r) => write_comparison(writer, 4, l, r),
+		BooleanExpression::Gth(l, r) => write_comparison(writer, 5, l, r),
+		BooleanExpression::Geq(l, r) => write_comparison(writer, 6, l, r),
+		BooleanExpression::Eq(l, r) => write_comparison(writer, 7, l, r),
+		BooleanExpression::Const(b) => writer.write_all(&[8, *b as u8])
+	}
+}
+
+fn write_comparison<W: Write>(writer: &mut W, tag: u8, l: &ArithmeticExpression, r: &ArithmeticExpression) -> io::Result<()> {
+	writer.write_all(&[tag])?;
+	write_arithmetic(writer, l)?;
+	write_arithmetic(writer, r)
+}
+
+fn write_annotation<W: Write>(writer: &mut W, annotation: Option<ModuleAnnotation>) -> io::Result<()> {
+	let code = match annotation {
+		None => 0,
+		Some(ModuleAnnotation::CreatePatch) => '~' as u32,
+		Some(ModuleAnnotation::Sphere) => 'O' as u32,
+		Some(ModuleAnnotation::Circle) => 'c' as u32,
+		Some(ModuleAnnotation::Instance) => 'I' as u32,
+		Some(ModuleAnnotation::Label) => 'L' as u32,
+		Some(ModuleAnnotation::Custom(id)) => {
+			writer.write_all(&CUSTOM_ANNOTATION_CODE.to_le_bytes())?;
+			return writer.write_all(&id.to_le_bytes());
+		}
+	};
+
+	writer.write_all(&code.to_le_bytes())
+}
+
+fn read_annotation<R: Read>(reader: &mut R) -> Result<Option<ModuleAnnotation>, CheckpointError> {
+	let code = read_u32(reader)?;
+
+	match code {
+		0 => return Ok(None),
+		CUSTOM_ANNOTATION_CODE => return Ok(Some(ModuleAnnotation::Custom(read_u32(reader)?))),
+		_ => ()
+	}
+
+	match std::char::from_u32(code) {
+		Some('~') => Ok(Some(ModuleAnnotation::CreatePatch)),
+		Some('O') => Ok(Some(ModuleAnnotation::Sphere)),
+		Some('c') => Ok(Some(ModuleAnnotation::Circle)),
+		Some('I') => Ok(Some(ModuleAnnotation::Instance)),
+		Some('L') => Ok(Some(ModuleAnnotation::Label)),
+		_ => Err(CheckpointError::InvalidData("unknown module annotation"))
+	}
+}
+
+fn write_module_string<W: Write>(writer: &mut W, module_string: &ModuleString) -> io::Result<()> {
+	writer.write_all(&(module_string.len() as u64).to_le_bytes())?;
+
+	for module in module_string.iter() {
+		writer.write_all(&(module.identifier as u32).to_le_bytes())?;
+		write_annotation(writer, module.annotation)?;
+		writer.write_all(&(module.parameter_count() as u32).to_le_bytes())?;
+
+		for value in module.parameter_values {
+			writer.write_all(&value.to_le_bytes())?;
+		}
+	}
+
+	Ok(())
+}
+
+/// Write a checkpoint of given derivation state. The format consists of the magic bytes and the format version,
+/// followed by the key of the random number generator, the rule set fingerprint, the generation index, a flag
+/// stating whether history is included, the axiom, the current module string and, if included, all preceding
+/// generations. All numbers are stored in little endian byte order.
+pub(crate) fn write_checkpoint<W: Write>(mut writer: W, rng_key: &RngKey, rules: &[Rule], generation: u32,
+	axiom: &ModuleString, module_string: &ModuleString, history: Option<&[ModuleString]>) -> io::Result<()> {
+	writer.write_all(CHECKPOINT_MAGIC)?;
+	writer.write_all(&CHECKPOINT_VERSION.to_le_bytes())?;
+	writer.write_all(rng_key)?;
+	writer.write_all(&fingerprint(rules).to_le_bytes())?;
+	writer.write_all(&generation.to_le_bytes())?;
+	writer.write_all(&[history.is_some() as u8])?;
+
+	write_module_string(&mut writer, axiom)?;
+	write_module_string(&mut writer, module_string)?;
+
+	if let Some(history) = history {
+		for module_string in history {
+			write_module_string(&mut writer, module_string)?;
+		}
+	}
+
+	writer.flush()
+}
+
+fn read_bytes<R: Read, const N: usize>(reader: &mut R) -> io::Result<[u8; N]> {
+	let mut buffer = [0; N];
+	reader.read_exact(&mut buffer)?;
+	Ok(buffer)
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+	Ok(u32::from_le_bytes(read_bytes(reader)?))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+	Ok(u64::from_le_bytes(read_bytes(reader)?))
+}
+
+fn read_module_string<R: Read>(reader: &mut R) -> Result<ModuleString, CheckpointError> {
+	let length = read_u64(reader)? as usize;
+	let mut module_string = ModuleString::with_capacity(length.min(MAX_RESERVE), 0);
+	let mut parameters = Vec::new();
+
+	for _ in 0..length {
+		let identifier = std::char::from_u32(read_u32(reader)?)
+			.ok_or(CheckpointError::InvalidData("invalid module identifier"))?;
+		let annotation = read_annotation(reader)?;
+		let count = read_u32(reader)?;
+
+		parameters.clear();
+
+		for _ in 0..count {
+			parameters.push(f64::from_le_bytes(read_bytes(reader)?));
+		}
+
+		module_string.push_module(identifier, annotation, parameters.iter().cloned());
+	}
+
+	Ok(module_string)
+}
+
+/// Read a checkpoint written by `write_checkpoint`, verifying that it was created with given rule set.
+pub(crate) fn read_checkpoint<R: Read>(mut reader: R, rules: &[Rule]) -> Result<Checkpoint, CheckpointError> {
+	let magic: [u8; 8] = read_bytes(&mut reader).map_err(|_| CheckpointError::InvalidHeader)?;
+
+	if(&magic != CHECKPOINT_MAGIC) {
+		return Err(CheckpointError::InvalidHeader);
+	}
+
+	let version = read_u32(&mut reader)?;
+
+	if(version != CHECKPOINT_VERSION) {
+		return Err(CheckpointError::UnsupportedVersion(version));
+	}
+
+	let rng_key: RngKey = read_bytes(&mut reader)?;
+
+	if(read_u64(&mut reader)? != fingerprint(rules)) {
+		return Err(CheckpointError::RuleSetMismatch);
+	}
+
+	let generation = read_u32(&mut reader)?;
+
+	let has_history = match read_bytes::<_, 1>(&mut reader)? {
+		[0] => false,
+		[1] => true,
+		_ => return Err(CheckpointError::InvalidData("invalid history flag"))
+	};
+
+	let axiom = read_module_string(&mut reader)?;
+	let module_string = read_module_string(&mut reader)?;
+
+	let history = if(has_history) {
+		let mut history = Vec::with_capacity((generation as usize).min(MAX_RESERVE));
+
+		for _ in 0..generation {
+			history.push(read_module_string(&mut reader)?);
+		}
+
+		Some(history)
+	} else {
+		None
+	};
+
+	Ok(Checkpoint {
+		rng_key,
+		generation,
+		axiom,
+		module_string,
+		history
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::annotation::AnnotationRegistry;
+	use crate::grammar::lsystem_parser;
+
+	const RULES: &str = "A(x) : 0.5 -> A(x+1)[+B(x)]A(x*0.5)\nA(x) : 0.5 -> B(x)@{2}A(x-1)\nB(x) : x > 2 -> B(x/2)";
+
+	fn engine(rules: &str, seed: u64) -> IterationEngine {
+		let annotations = AnnotationRegistry::new();
+		let mut engine = IterationEngine::new();
+		engine.set_axiom(ModuleString::from(lsystem_parser::module_string("A(1)~B(0.25)", &annotations).unwrap()));
+		engine.set_rules(lsystem_parser::rule_list(rules, &annotations).unwrap());
+		engine.set_seed(seed);
+		engine
+	}
+
+	#[test]
+	fn restored_derivation_continues_identically() {
+		let mut original = engine(RULES, 7);
+		original.iterate_to(4).unwrap();
+		let checkpoint = original.snapshot();
+
+		// The seed of the restoring engine is replaced by the key stored in the checkpoint
+		let mut restored = engine(RULES, 8);
+		restored.restore(&checkpoint).unwrap();
+		assert_eq!(restored.module_string(), original.module_string());
+		assert_eq!(restored.snapshot(), checkpoint);
+
+		original.iterate_to(8).unwrap();
+		restored.iterate_to(8).unwrap();
+		assert_eq!(restored.module_string(), original.module_string());
+	}
+
+	#[test]
+	fn restore_rejects_different_rule_set() {
+		let mut original = engine(RULES, 7);
+		original.iterate_to(2).unwrap();
+
+		let mut other = engine(&RULES.replace("x > 2", "x > 3"), 7);
+		assert!(matches!(other.restore(&original.snapshot()), Err(CheckpointError::RuleSetMismatch)));
+	}
+
+	#[test]
+	fn restore_rejects_other_versions() {
+		let original = engine(RULES, 7);
+		let mut checkpoint = original.snapshot();
+		assert_eq!(&checkpoint[8..12], &CHECKPOINT_VERSION.to_le_bytes());
+
+		checkpoint[8..12].copy_from_slice(&(CHECKPOINT_VERSION + 1).to_le_bytes());
+
+		let mut restored = engine(RULES, 7);
+		assert!(matches!(restored.restore(&checkpoint), Err(CheckpointError::UnsupportedVersion(v)) if v == CHECKPOINT_VERSION + 1));
+	}
+}
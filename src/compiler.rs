@@ -0,0 +1,269 @@
+use std::fmt::*;
+use crate::iteration::*;
+use crate::drawing::DrawingVariable;
+
+/// A single instruction of a compiled expression. Instructions operate on a value stack, with boolean
+/// values being represented as 1.0 (true) and 0.0 (false).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Instruction {
+	/// Push a constant value.
+	Const(f64),
+	/// Push the value bound to given slot.
+	Load(usize),
+	/// Push the value of the drawing variable with given index, see `DrawingVariable::ALL`.
+	Variable(usize),
+	Add,
+	Sub,
+	Mul,
+	Div,
+	Pow,
+	Neg,
+	Lth,
+	Leq,
+	Gth,
+	Geq,
+	Eq,
+	Not,
+	And,
+	Or
+}
+
+/// An arithmetic or boolean expression compiled into a flat sequence of instructions. All parameter
+/// references are resolved to slot indices at compile time, and all constant subexpressions are folded.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompiledExpression {
+	code: Vec<Instruction>,
+	stack_size: usize
+}
+
+fn as_bool(value: f64) -> bool {
+	value != 0.0
+}
+
+fn from_bool(value: bool) -> f64 {
+	if value { 1.0 } else { 0.0 }
+}
+
+/// Intermediate result of compiling a subexpression: either a value known at compile time,
+/// or code that has to be executed at run time.
+enum Folded {
+	Constant(f64),
+	Code(Vec<Instruction>)
+}
+
+impl Folded {
+	fn into_code(self) -> Vec<Instruction> {
+		match self {
+			Folded::Constant(x) => vec![Instruction::Const(x)],
+			Folded::Code(code) => code
+		}
+	}
+
+	fn unary(self, op: Instruction, fold: fn(f64) -> f64) -> Folded {
+		match self {
+			Folded::Constant(x) => Folded::Constant(fold(x)),
+			Folded::Code(mut code) => {
+				code.push(op);
+				Folded::Code(code)
+			}
+		}
+	}
+
+	fn binary(self, right: Folded, op: Instruction, fold: fn(f64, f64) -> f64) -> Folded {
+		match (self, right) {
+			(Folded::Constant(l), Folded::Constant(r)) => Folded::Constant(fold(l, r)),
+			(left, right) => {
+				let mut code = left.into_code();
+				code.extend(right.into_code());
+				code.push(op);
+				Folded::Code(code)
+			}
+		}
+	}
+}
+
+fn resolve(param: char, slots: &[char]) -> std::result::Result<usize, EvaluationError> {
+	slots.iter()
+		.position(|p| *p == param)
+		.ok_or(EvaluationError::UnboundParameter(param))
+}
+
+/// Resolve given drawing variable to its index, if variables are available.
+fn resolve_variable(variable: DrawingVariable, variables: bool) -> std::result::Result<usize, EvaluationError> {
+	if(variables) {
+		Ok(variable as usize)
+	} else {
+		Err(EvaluationError::UnboundVariable(variable))
+	}
+}
+
+fn fold_arithmetic(expr: &ArithmeticExpression, slots: &[char], variables: bool) -> std::result::Result<Folded, EvaluationError> {
+	let fold = |e| fold_arithmetic(e, slots, variables);
+
+	Ok(match *expr {
+		ArithmeticExpression::Add(ref l, ref r) => fold(l)?.binary(fold(r)?, Instruction::Add, |l, r| l + r),
+		ArithmeticExpression::Sub(ref l, ref r) => fold(l)?.binary(fold(r)?, Instruction::Sub, |l, r| l - r),
+		ArithmeticExpression::Mul(ref l, ref r) => fold(l)?.binary(fold(r)?, Instruction::Mul, |l, r| l * r),
+		ArithmeticExpression::Div(ref l, ref r) => fold(l)?.binary(fold(r)?, Instruction::Div, |l, r| l / r),
+		ArithmeticExpression::Pow(ref l, ref r) => fold(l)?.binary(fold(r)?, Instruction::Pow, f64::powf),
+		ArithmeticExpression::Neg(ref e) => fold(e)?.unary(Instruction::Neg, |x| -x),
+		ArithmeticExpression::Const(x) => Folded::Constant(x),
+		ArithmeticExpression::Param(p) => Folded::Code(vec![Instruction::Load(resolve(p, slots)?)]),
+		ArithmeticExpression::Variable(v) => Folded::Code(vec![Instruction::Variable(resolve_variable(v, variables)?)])
+	})
+}
+
+fn fold_boolean(expr: &BooleanExpression, slots: &[char]) -> std::result::Result<Folded, EvaluationError> {
+	Ok(match *expr {
+		BooleanExpression::Not(ref e) => fold_boolean(e, slots)?.unary(Instruction::Not, |x| from_bool(!as_bool(x))),
+		BooleanExpression::And(ref l, ref r) => {
+			match (fold_boolean(l, slots)?, fold_boolean(r, slots)?) {
+				// A constant false operand decides the result on its own
+				(Folded::Constant(x), _) | (_, Folded::Constant(x)) if !as_bool(x) => Folded::Constant(0.0),
+				// A constant true operand can be dropped
+				(Folded::Constant(_), other) | (other, Folded::Constant(_)) => other,
+				(l, r) => l.binary(r, Instruction::And, |l, r| from_bool(as_bool(l) && as_bool(r)))
+			}
+		},
+		BooleanExpression::Or(ref l, ref r) => {
+			match (fold_boolean(l, slots)?, fold_boolean(r, slots)?) {
+				(Folded::Constant(x), _) | (_, Folded::Constant(x)) if as_bool(x) => Folded::Constant(1.0),
+				(Folded::Constant(_), other) | (other, Folded::Constant(_)) => other,
+				(l, r) => l.binary(r, Instruction::Or, |l, r| from_bool(as_bool(l) || as_bool(r)))
+			}
+		},
+		BooleanExpression::Lth(ref l, ref r) => fold_arithmetic(l, slots, false)?.binary(fold_arithmetic(r, slots, false)?, Instruction::Lth, |l, r| from_bool(l < r)),
+		BooleanExpression::Leq(ref l, ref r) => fold_arithmetic(l, slots, false)?.binary(fold_arithmetic(r, slots, false)?, Instruction::Leq, |l, r| from_bool(l <= r)),
+		BooleanExpression::Gth(ref l, ref r) => fold_arithmetic(l, slots, false)?.binary(fold_arithmetic(r, slots, false)?, Instruction::Gth, |l, r| from_bool(l > r)),
+		BooleanExpression::Geq(ref l, ref r) => fold_arithmetic(l, slots, false)?.binary(fold_arithmetic(r, slots, false)?, Instruction::Geq, |l, r| from_bool(l >= r)),
+		BooleanExpression::Eq(ref l, ref r) => fold_arithmetic(l, slots, false)?.binary(fold_arithmetic(r, slots, false)?, Instruction::Eq, |l, r| from_bool(l == r)),
+		BooleanExpression::Const(b) => Folded::Constant(from_bool(b))
+	})
+}
+
+impl CompiledExpression {
+	/// Compile given arithmetic expression. Parameters are resolved to the index of their name in `slots`.
+	pub fn arithmetic(expr: &ArithmeticExpression, slots: &[char]) -> std::result::Result<CompiledExpression, EvaluationError> {
+		Ok(Self::from_code(fold_arithmetic(expr, slots, false)?.into_code()))
+	}
+
+	/// Compile given arithmetic expression, which may reference drawing variables. Parameters are resolved to the index
+	/// of their name in `slots`, while drawing variables are read from the values passed to `eval_with_variables`.
+	pub fn arithmetic_with_variables(expr: &ArithmeticExpression, slots: &[char]) -> std::result::Result<CompiledExpression, EvaluationError> {
+		Ok(Self::from_code(fold_arithmetic(expr, slots, true)?.into_code()))
+	}
+
+	/// Compile given boolean expression. Parameters are resolved to the index of their name in `slots`.
+	pub fn boolean(expr: &BooleanExpression, slots: &[char]) -> std::result::Result<CompiledExpression, EvaluationError> {
+		Ok(Self::from_code(fold_boolean(expr, slots)?.into_code()))
+	}
+
+	fn from_code(code: Vec<Instruction>) -> CompiledExpression {
+		// Determine the maximum stack depth required to execute this program
+		let mut depth: usize = 0;
+		let mut stack_size: usize = 0;
+
+		for instruction in &code {
+			match instruction {
+				Instruction::Const(_) | Instruction::Load(_) | Instruction::Variable(_) => depth += 1,
+				Instruction::Neg | Instruction::Not => (),
+				_ => depth -= 1
+			}
+
+			stack_size = stack_size.max(depth);
+		}
+
+		CompiledExpression {
+			code,
+			stack_size
+		}
+	}
+
+	/// Retrieve the value of this expression if it does not depend on any parameters.
+	pub fn constant(&self) -> Option<f64> {
+		match self.code[..] {
+			[Instruction::Const(x)] => Some(x),
+			_ => None
+		}
+	}
+
+	/// Retrieve the instructions of this program.
+	pub fn instructions(&self) -> &[Instruction] {
+		&self.code
+	}
+
+	/// Evaluate this expression with given slot values. The given stack is used as scratch space,
+	/// which allows it to be reused for many evaluations.
+	pub fn eval(&self, slots: &[f64], stack: &mut Vec<f64>) -> f64 {
+		self.eval_with_variables(slots, &[], stack)
+	}
+
+	/// Evaluate this expression with given slot values and drawing variable values, in the order of
+	/// `DrawingVariable::ALL`.
+	pub fn eval_with_variables(&self, slots: &[f64], variables: &[f64], stack: &mut Vec<f64>) -> f64 {
+		if let Some(x) = self.constant() {
+			return x;
+		}
+
+		stack.clear();
+		stack.reserve(self.stack_size);
+
+		for instruction in &self.code {
+			let value = match *instruction {
+				Instruction::Const(x) => x,
+				Instruction::Load(slot) => slots[slot],
+				Instruction::Variable(index) => variables[index],
+				Instruction::Neg => -stack.pop().unwrap(),
+				Instruction::Not => from_bool(!as_bool(stack.pop().unwrap())),
+				binary => {
+					let r = stack.pop().unwrap();
+					let l = stack.pop().unwrap();
+
+					match binary {
+						Instruction::Add => l + r,
+						Instruction::Sub => l - r,
+						Instruction::Mul => l * r,
+						Instruction::Div => l / r,
+						Instruction::Pow => l.powf(r),
+						Instruction::Lth => from_bool(l < r),
+						Instruction::Leq => from_bool(l <= r),
+						Instruction::Gth => from_bool(l > r),
+						Instruction::Geq => from_bool(l >= r),
+						Instruction::Eq => from_bool(l == r),
+						Instruction::And => from_bool(as_bool(l) && as_bool(r)),
+						Instruction::Or => from_bool(as_bool(l) || as_bool(r)),
+						_ => unreachable!()
+					}
+				}
+			};
+
+			stack.push(value);
+		}
+
+		stack[0]
+	}
+
+	/// Evaluate this expression as a condition.
+	pub fn eval_bool(&self, slots: &[f64], stack: &mut Vec<f64>) -> bool {
+		as_bool(self.eval(slots, stack))
+	}
+}
+
+impl Display for CompiledExpression {
+	fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+		for (i, instruction) in self.code.iter().enumerate() {
+			if(i > 0) {
+				write!(f, " ")?;
+			}
+
+			match instruction {
+				Instruction::Const(x) => write!(f, "{}", x)?,
+				Instruction::Load(slot) => write!(f, "${}", slot)?,
+				Instruction::Variable(index) => write!(f, "{}", DrawingVariable::ALL[*index].name())?,
+				other => write!(f, "{:?}", other)?
+			}
+		}
+
+		Ok(())
+	}
+}
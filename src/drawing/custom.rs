@@ -0,0 +1,57 @@
+use std::collections::HashMap;
+use crate::drawing::turtle::*;
+
+/// A drawing operation implemented by the application, such as placing sensors or tagging regions.
+/// Custom commands are associated with module identifiers using `InterpretationEngine::associate_custom` and are executed
+/// by the turtle whenever such a module is interpreted.
+pub trait CustomCommand: Send {
+	/// Execute the command with the parameter values of the interpreted module, which is located at given index in
+	/// the module string. The turtle state can be modified freely, but heading, left and up are expected to stay an
+	/// orthonormal basis. Primitives can be emitted by adding them to given drawing result.
+	fn execute(&mut self, state: &mut Turtle3DState, parameters: &[f64], module_index: usize, result: &mut DrawingResult);
+}
+
+impl<F> CustomCommand for F
+	where F: FnMut(&mut Turtle3DState, &[f64], usize, &mut DrawingResult) + Send {
+	fn execute(&mut self, state: &mut Turtle3DState, parameters: &[f64], module_index: usize, result: &mut DrawingResult) {
+		self(state, parameters, module_index, result)
+	}
+}
+
+/// A collection of custom commands, identified by the id used in `InterpretationEngine::associate_custom`.
+#[derive(Default)]
+pub struct CustomCommandRegistry {
+	commands: HashMap<u32, Box<dyn CustomCommand>>
+}
+
+impl CustomCommandRegistry {
+	pub fn new() -> CustomCommandRegistry {
+		CustomCommandRegistry {
+			commands: HashMap::new()
+		}
+	}
+
+	/// Register given command under given id, replacing any command previously registered with that id.
+	pub fn register<C: CustomCommand + 'static>(&mut self, id: u32, command: C) {
+		self.commands.insert(id, Box::new(command));
+	}
+
+	/// Remove the command registered with given id, if any.
+	pub fn unregister(&mut self, id: u32) -> Option<Box<dyn CustomCommand>> {
+		self.commands.remove(&id)
+	}
+
+	/// Check whether a command is registered with given id.
+	pub fn contains(&self, id: u32) -> bool {
+		self.commands.contains_key(&id)
+	}
+
+	/// Retrieve the command registered with given id.
+	pub fn get_mut(&mut self, id: u32) -> Option<&mut (dyn CustomCommand + 'static)> {
+		self.commands.get_mut(&id).map(|c| c.as_mut())
+	}
+
+	pub fn clear(&mut self) {
+		self.commands.clear();
+	}
+}
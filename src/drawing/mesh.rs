@@ -0,0 +1,617 @@
+use std::collections::HashMap;
+use std::f32::consts::PI;
+use nalgebra::*;
+
+use crate::drawing::primitives::*;
+use crate::drawing::types::*;
+#[cfg(feature = "serde")]
+use serde_derive::*;
+
+/// An indexed triangle mesh. Triangles are stored as triples of vertex indices, wound counterclockwise when
+/// viewed from the side their normals point to.
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct TriangleMesh {
+	pub vertices: Vec<Vector3f>,
+	/// Per-vertex normals
+	pub normals: Vec<Vector3f>,
+	/// Per-vertex color palette indices
+	pub colors: Vec<i32>,
+	/// Vertex indices, three per triangle
+	pub indices: Vec<u32>,
+	/// Index of the module that created each triangle
+	pub module_indices: Vec<usize>
+}
+
+impl TriangleMesh {
+	pub fn new() -> TriangleMesh {
+		TriangleMesh::default()
+	}
+
+	pub fn triangle_count(&self) -> usize {
+		self.indices.len() / 3
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.indices.is_empty()
+	}
+
+	/// Append all vertices and triangles of given mesh to this mesh.
+	pub fn append(&mut self, other: &TriangleMesh) {
+		let offset = self.vertices.len() as u32;
+
+		self.vertices.extend_from_slice(&other.vertices);
+		self.normals.extend_from_slice(&other.normals);
+		self.colors.extend_from_slice(&other.colors);
+		self.indices.extend(other.indices.iter().map(|i| i + offset));
+		self.module_indices.extend_from_slice(&other.module_indices);
+	}
+
+	fn push_vertex(&mut self, position: Vector3f, normal: Vector3f, color: i32) -> u32 {
+		self.vertices.push(position);
+		self.normals.push(normal);
+		self.colors.push(color);
+		(self.vertices.len() - 1) as u32
+	}
+
+	fn push_triangle(&mut self, a: u32, b: u32, c: u32, module_index: usize) {
+		self.indices.extend_from_slice(&[a, b, c]);
+		self.module_indices.push(module_index);
+	}
+}
+
+/// Choose an arbitrary unit vector perpendicular to given unit direction.
+fn perpendicular(direction: &Vector3f) -> Vector3f {
+	let axis = if(direction.x.abs() < 0.9) { Vector3f::x() } else { Vector3f::y() };
+	direction.cross(&axis).normalize()
+}
+
+/// Triangulate given polygon using ear clipping. The vertices are projected onto the plane that fits them best, which
+/// makes this robust against concave outlines and slightly non-planar polygons. Triangles are wound counterclockwise
+/// around the plane normal, which follows the winding of the polygon. Vertex normals are the mean of the normals of the
+/// adjacent triangles. Polygons with fewer than three distinct vertices or without area result in an empty mesh.
+pub fn triangulate(polygon: &Polygon) -> TriangleMesh {
+	let mut mesh = TriangleMesh::new();
+
+	let mut vertices: Vec<Vector3f> = Vec::with_capacity(polygon.vertices.len());
+
+	for v in &polygon.vertices {
+		if(vertices.last() != Some(v)) {
+			vertices.push(*v);
+		}
+	}
+
+	while(vertices.len() > 1 && vertices.first() == vertices.last()) {
+		vertices.pop();
+	}
+
+	if(vertices.len() < 3) {
+		return mesh;
+	}
+
+	// Newell's method yields the normal of the best fitting plane, scaled by the projected area
+	let mut plane_normal = Vector3f::zeros();
+
+	for (i, a) in vertices.iter().enumerate() {
+		let b = &vertices[(i + 1) % vertices.len()];
+		plane_normal += Vector3f::new(
+			(a.y - b.y) * (a.z + b.z),
+			(a.z - b.z) * (a.x + b.x),
+			(a.x - b.x) * (a.y + b.y)
+		);
+	}
+
+	let plane_normal = match plane_normal.try_normalize(f32::EPSILON) {
+		Some(n) => n,
+		None => return mesh
+	};
+
+	let u = perpendicular(&plane_normal);
+	let v = plane_normal.cross(&u);
+
+	let projected: Vec<Vector2f> = vertices.iter()
+		.map(|p| Vector2f::new(p.dot(&u), p.dot(&v)))
+		.collect();
+
+	let mut normals = vec![Vector3f::zeros(); vertices.len()];
+	let mut triangles: Vec<[usize; 3]> = Vec::with_capacity(vertices.len() - 2);
+	let mut remaining: Vec<usize> = (0..vertices.len()).collect();
+
+	let cross = |a: usize, b: usize, c: usize| -> f32 {
+		let (a, b, c) = (projected[a], projected[b], projected[c]);
+		(b.x - a.x) * (c.y - a.y) - (b.y - a.y) * (c.x - a.x)
+	};
+
+	while(remaining.len() > 3) {
+		let count = remaining.len();
+
+		let is_ear = |i: usize| -> bool {
+			let (a, b, c) = (remaining[(i + count - 1) % count], remaining[i], remaining[(i + 1) % count]);
+
+			if(cross(a, b, c) <= 0.0) {
+				return false;
+			}
+
+			// No other vertex may lie inside or on the candidate triangle
+			!remaining.iter()
+				.filter(|p| **p != a && **p != b && **p != c && projected[**p] != projected[a]
+					&& projected[**p] != projected[b] && projected[**p] != projected[c])
+				.any(|p| cross(a, b, *p) >= 0.0 && cross(b, c, *p) >= 0.0 && cross(c, a, *p) >= 0.0)
+		};
+
+		// Self-intersecting outlines may not have any ears, in which case the most convex vertex is clipped
+		let ear = (0..count).find(|i| is_ear(*i)).unwrap_or_else(|| {
+			(0..count)
+				.max_by(|i, j| {
+					let value = |k: usize| cross(remaining[(k + count - 1) % count], remaining[k], remaining[(k + 1) % count]);
+					value(*i).partial_cmp(&value(*j)).unwrap_or(std::cmp::Ordering::Equal)
+				})
+				.unwrap()
+		});
+
+		triangles.push([remaining[(ear + count - 1) % count], remaining[ear], remaining[(ear + 1) % count]]);
+		remaining.remove(ear);
+	}
+
+	triangles.push([remaining[0], remaining[1], remaining[2]]);
+
+	for [a, b, c] in &triangles {
+		let face = (vertices[*b] - vertices[*a]).cross(&(vertices[*c] - vertices[*a]));
+
+		for i in &[*a, *b, *c] {
+			normals[*i] += face;
+		}
+	}
+
+	for (position, normal) in vertices.iter().zip(normals.iter()) {
+		mesh.push_vertex(*position, normal.try_normalize(f32::EPSILON).unwrap_or(plane_normal), polygon.color);
+	}
+
+	for [a, b, c] in &triangles {
+		mesh.push_triangle(*a as u32, *b as u32, *c as u32, polygon.module_index);
+	}
+
+	mesh
+}
+
+/// Builds generalized cylinders around connected line segments, as an alternative to rendering them as flat lines.
+///
+/// Segments are joined into branches by connecting each segment to the segment starting at its end point whose
+/// direction is closest to its own. The cross-section frames are carried along each branch using parallel transport,
+/// which avoids twisting, and side branches start with the frame of their parent. Consecutive segments of a branch share
+/// the vertices of the cross-section at their joint, unless radius or color change there.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct TubeMeshBuilder {
+	/// Number of vertices of each cross-section. Values below 3 are treated as 3.
+	pub resolution: u32,
+	/// Whether the radius changes linearly from the width of a segment to the width of the next segment of the same
+	/// branch. Otherwise, each segment has a constant radius.
+	pub interpolate_width: bool,
+	/// Whether to close the open ends of branches, i.e. their tips and the start of the root branch, with flat caps
+	pub caps: bool
+}
+
+impl TubeMeshBuilder {
+	pub fn new() -> TubeMeshBuilder {
+		TubeMeshBuilder {
+			resolution: 8,
+			interpolate_width: true,
+			caps: true
+		}
+	}
+
+	/// Build a mesh for given line segments, using half their width as radius.
+	pub fn build(&self, segments: &[LineSegment]) -> TriangleMesh {
+		let mut mesh = TriangleMesh::new();
+		let resolution = self.resolution.max(3);
+
+		let direction = |s: &LineSegment| -> Option<Vector3f> {
+			(s.end - s.begin).try_normalize(f32::EPSILON)
+		};
+
+		let valid: Vec<usize> = (0..segments.len())
+			.filter(|i| direction(&segments[*i]).is_some())
+			.collect();
+
+		let mut starts: HashMap<[u32; 3], Vec<usize>> = HashMap::new();
+
+		for i in &valid {
+			starts.entry(Self::key(&segments[*i].begin)).or_default().push(*i);
+		}
+
+		// Determine the continuation of each segment and whether it is preceded by another segment
+		let mut next: Vec<Option<usize>> = vec![None; segments.len()];
+		let mut claimed = vec![false; segments.len()];
+		let mut preceded = vec![false; segments.len()];
+
+		for i in &valid {
+			let successors = match starts.get(&Self::key(&segments[*i].end)) {
+				Some(s) => s,
+				None => continue
+			};
+
+			let d = direction(&segments[*i]).unwrap();
+
+			for j in successors {
+				preceded[*j] = true;
+			}
+
+			let best = successors.iter()
+				.filter(|j| **j != *i && !claimed[**j])
+				.map(|j| (*j, d.dot(&direction(&segments[*j]).unwrap())))
+				.fold(None, |best: Option<(usize, f32)>, (j, dot)| match best {
+					Some((_, b)) if b >= dot => best,
+					_ => Some((j, dot))
+				});
+
+			if let Some((j, _)) = best {
+				next[*i] = Some(j);
+				claimed[j] = true;
+			}
+		}
+
+		// Frame normals at the end points of processed segments, used to start side branches
+		let mut joint_normals: HashMap<[u32; 3], Vector3f> = HashMap::new();
+		let mut visited = vec![false; segments.len()];
+
+		// Branches start at unclaimed segments. Afterwards, remaining segments form closed loops
+		let order = valid.iter().filter(|i| !claimed[**i]).chain(valid.iter());
+
+		for first in order {
+			if(visited[*first]) {
+				continue;
+			}
+
+			let d = direction(&segments[*first]).unwrap();
+
+			let mut normal = match joint_normals.get(&Self::key(&segments[*first].begin)) {
+				Some(n) => Self::orthogonalize(n, &d),
+				None => perpendicular(&d)
+			};
+
+			let mut current = Some(*first);
+			let mut previous_direction = d;
+
+			// End ring of the previous segment of the branch, with its radius and color
+			let mut previous_ring: Option<(u32, f32, i32)> = None;
+
+			while let Some(i) = current {
+				if(visited[i]) {
+					break;
+				}
+
+				visited[i] = true;
+
+				let segment = &segments[i];
+				let d = direction(segment).unwrap();
+
+				let following = next[i].filter(|j| !visited[*j]);
+				let next_direction = following.map(|j| direction(&segments[j]).unwrap());
+
+				// Cross-sections at joints are perpendicular to the mean of both directions
+				let begin_tangent = (previous_direction + d).try_normalize(f32::EPSILON).unwrap_or(d);
+				let end_tangent = next_direction
+					.and_then(|n| (d + n).try_normalize(f32::EPSILON))
+					.unwrap_or(d);
+
+				let begin_normal = Self::transport(&normal, &previous_direction, &begin_tangent);
+				let end_normal = Self::transport(&begin_normal, &begin_tangent, &end_tangent);
+
+				let begin = CrossSection {
+					center: segment.begin,
+					tangent: begin_tangent,
+					normal: begin_normal,
+					radius: segment.width * 0.5
+				};
+
+				let end = CrossSection {
+					center: segment.end,
+					tangent: end_tangent,
+					normal: end_normal,
+					radius: match following {
+						Some(j) if self.interpolate_width => segments[j].width * 0.5,
+						_ => begin.radius
+					}
+				};
+
+				// Consecutive segments share the ring at their joint, unless radius or color change there
+				let begin_ring = match previous_ring {
+					Some((ring, radius, color)) if radius == begin.radius && color == segment.color => ring,
+					_ => Self::push_ring(&mut mesh, resolution, &begin, segment.color)
+				};
+
+				let end_ring = Self::push_ring(&mut mesh, resolution, &end, segment.color);
+
+				for k in 0..resolution {
+					let l = (k + 1) % resolution;
+
+					mesh.push_triangle(begin_ring + k, begin_ring + l, end_ring + l, segment.module_index);
+					mesh.push_triangle(begin_ring + k, end_ring + l, end_ring + k, segment.module_index);
+				}
+
+				if(self.caps && i == *first && !preceded[i]) {
+					let base = CrossSection { tangent: -begin.tangent, ..begin };
+					Self::push_cap(&mut mesh, resolution, &base, segment.color, segment.module_index);
+				}
+
+				if(self.caps && next[i].is_none()) {
+					Self::push_cap(&mut mesh, resolution, &end, segment.color, segment.module_index);
+				}
+
+				normal = Self::transport(&end_normal, &end_tangent, &d);
+				joint_normals.entry(Self::key(&segment.end)).or_insert(normal);
+				previous_direction = d;
+				previous_ring = Some((end_ring, end.radius, segment.color));
+				current = following;
+			}
+		}
+
+		mesh
+	}
+
+	/// Key used to find segments starting at a point. Segments drawn consecutively by the turtle share their end
+	/// points exactly.
+	fn key(point: &Vector3f) -> [u32; 3] {
+		[point.x.to_bits(), point.y.to_bits(), point.z.to_bits()]
+	}
+
+	/// Rotate given normal along with the rotation from one tangent to another.
+	fn transport(normal: &Vector3f, from: &Vector3f, to: &Vector3f) -> Vector3f {
+		let rotated = match Rotation3::rotation_between(from, to) {
+			Some(rotation) => rotation * normal,
+			None => *normal
+		};
+
+		Self::orthogonalize(&rotated, to)
+	}
+
+	/// Make given vector perpendicular to given unit direction.
+	fn orthogonalize(vector: &Vector3f, direction: &Vector3f) -> Vector3f {
+		(vector - direction * vector.dot(direction))
+			.try_normalize(f32::EPSILON)
+			.unwrap_or_else(|| perpendicular(direction))
+	}
+
+	/// Add the vertices of given cross-section and return the index of the first one. The vertices are ordered
+	/// counterclockwise around the tangent.
+	fn push_ring(mesh: &mut TriangleMesh, resolution: u32, section: &CrossSection, color: i32) -> u32 {
+		let first = mesh.vertices.len() as u32;
+
+		for radial in section.radials(resolution) {
+			mesh.push_vertex(section.center + radial * section.radius, radial, color);
+		}
+
+		first
+	}
+
+	/// Add a flat cap closing given cross-section, facing in direction of its tangent.
+	fn push_cap(mesh: &mut TriangleMesh, resolution: u32, section: &CrossSection, color: i32, module_index: usize) {
+		let center = mesh.push_vertex(section.center, section.tangent, color);
+
+		for radial in section.radials(resolution) {
+			mesh.push_vertex(section.center + radial * section.radius, section.tangent, color);
+		}
+
+		for k in 0..resolution {
+			let l = (k + 1) % resolution;
+			mesh.push_triangle(center, center + 1 + k, center + 1 + l, module_index);
+		}
+	}
+}
+
+/// A circular cross-section of a tube, perpendicular to its tangent.
+struct CrossSection {
+	center: Vector3f,
+	tangent: Vector3f,
+	/// Unit vector perpendicular to the tangent, pointing to the first vertex
+	normal: Vector3f,
+	radius: f32
+}
+
+impl CrossSection {
+	/// Unit vectors from the center to the vertices of this cross-section, counterclockwise around the tangent.
+	fn radials(&self, resolution: u32) -> impl Iterator<Item = Vector3f> {
+		let normal = self.normal;
+		let binormal = self.tangent.cross(&self.normal);
+
+		(0..resolution).map(move |k| {
+			let angle = 2.0 * PI * (k as f32) / (resolution as f32);
+			normal * angle.cos() + binormal * angle.sin()
+		})
+	}
+}
+
+impl Default for TubeMeshBuilder {
+	fn default() -> TubeMeshBuilder {
+		TubeMeshBuilder::new()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn polygon(outline: &[(f32, f32)]) -> Polygon {
+		Polygon {
+			vertices: outline.iter().map(|(x, y)| Vector3f::new(*x, *y, 0.0)).collect(),
+			color: 0,
+			module_index: 0
+		}
+	}
+
+	/// Signed areas of all triangles of given mesh, as seen from given direction.
+	fn triangle_areas(mesh: &TriangleMesh, direction: Vector3f) -> Vec<f32> {
+		mesh.indices.chunks(3)
+			.map(|t| {
+				let (a, b, c) = (mesh.vertices[t[0] as usize], mesh.vertices[t[1] as usize], mesh.vertices[t[2] as usize]);
+				0.5 * (b - a).cross(&(c - a)).dot(&direction)
+			})
+			.collect()
+	}
+
+	fn assert_covers(mesh: &TriangleMesh, direction: Vector3f, area: f32) {
+		let areas = triangle_areas(mesh, direction);
+
+		assert!(areas.iter().all(|a| *a > 0.0), "triangles facing away or degenerate: {:?}", areas);
+		assert!((areas.iter().sum::<f32>() - area).abs() < 1e-5);
+		assert!(mesh.normals.iter().all(|n| (n - direction).norm() < 1e-5));
+	}
+
+	#[test]
+	fn triangulates_concave_outline() {
+		let mesh = triangulate(&polygon(&[(0.0, 0.0), (2.0, 0.0), (2.0, 1.0), (1.0, 1.0), (1.0, 2.0), (0.0, 2.0)]));
+
+		assert_eq!(mesh.triangle_count(), 4);
+		assert_covers(&mesh, Vector3f::z(), 3.0);
+	}
+
+	#[test]
+	fn triangulates_outline_with_collinear_vertices() {
+		let outline = [(0.0, 0.0), (1.0, 0.0), (2.0, 0.0), (2.0, 1.0), (2.0, 2.0), (1.0, 2.0), (0.0, 2.0), (0.0, 1.0)];
+		let mesh = triangulate(&polygon(&outline));
+
+		assert_eq!(mesh.triangle_count(), outline.len() - 2);
+		assert_covers(&mesh, Vector3f::z(), 4.0);
+	}
+
+	#[test]
+	fn follows_winding_of_polygon() {
+		let mesh = triangulate(&polygon(&[(0.0, 2.0), (1.0, 2.0), (1.0, 1.0), (2.0, 1.0), (2.0, 0.0), (0.0, 0.0)]));
+
+		assert_covers(&mesh, -Vector3f::z(), 3.0);
+	}
+
+	#[test]
+	fn ignores_degenerate_outlines() {
+		assert!(triangulate(&polygon(&[(0.0, 0.0), (1.0, 0.0), (2.0, 0.0), (0.0, 0.0)])).is_empty());
+		assert!(triangulate(&polygon(&[(0.0, 0.0), (1.0, 1.0), (1.0, 1.0)])).is_empty());
+	}
+
+	fn segment(begin: (f32, f32, f32), end: (f32, f32, f32), width: f32) -> LineSegment {
+		LineSegment {
+			begin: Vector3f::new(begin.0, begin.1, begin.2),
+			end: Vector3f::new(end.0, end.1, end.2),
+			color: 0,
+			width,
+			module_index: 0
+		}
+	}
+
+	fn builder(resolution: u32, caps: bool) -> TubeMeshBuilder {
+		TubeMeshBuilder { resolution, interpolate_width: true, caps }
+	}
+
+	/// Unit vectors from given center to the vertices of the ring starting at given vertex index.
+	fn radials(mesh: &TriangleMesh, first: usize, resolution: usize, center: Vector3f) -> Vec<Vector3f> {
+		mesh.vertices[first..first + resolution].iter().map(|v| (v - center).normalize()).collect()
+	}
+
+	fn assert_radius(mesh: &TriangleMesh, first: usize, resolution: usize, center: Vector3f, radius: f32) {
+		for v in &mesh.vertices[first..first + resolution] {
+			assert!(((v - center).norm() - radius).abs() < 1e-5, "expected radius {} at ring {}", radius, first / resolution);
+		}
+	}
+
+	#[test]
+	fn shares_rings_at_joints() {
+		let segments = [segment((0.0, 0.0, 0.0), (0.0, 0.0, 1.0), 1.0), segment((0.0, 0.0, 1.0), (0.0, 0.0, 2.0), 1.0)];
+		let resolution = 6;
+
+		let mesh = builder(resolution, true).build(&segments);
+		let caps = 2 * (resolution as usize + 1);
+
+		assert_eq!(mesh.vertices.len(), (segments.len() + 1) * resolution as usize + caps);
+		assert_eq!(mesh.triangle_count(), segments.len() * 2 * resolution as usize + 2 * resolution as usize);
+	}
+
+	#[test]
+	fn caps_only_branch_tips() {
+		let segments = [
+			segment((0.0, 0.0, 0.0), (0.0, 0.0, 1.0), 0.5),
+			segment((0.0, 0.0, 1.0), (0.0, 0.0, 2.0), 0.5),
+			segment((0.0, 0.0, 1.0), (1.0, 0.0, 2.0), 0.5)
+		];
+		let resolution = 5;
+
+		let mesh = builder(resolution, true).build(&segments);
+
+		// Ring vertices are offset from the segment end points, so only cap centers lie on them
+		let mut centers: Vec<Vector3f> = mesh.vertices.iter()
+			.filter(|v| segments.iter().any(|s| s.begin == **v || s.end == **v))
+			.cloned()
+			.collect();
+		centers.sort_by(|a, b| a.as_slice().partial_cmp(b.as_slice()).unwrap());
+
+		assert_eq!(centers, vec![Vector3f::new(0.0, 0.0, 0.0), Vector3f::new(0.0, 0.0, 2.0), Vector3f::new(1.0, 0.0, 2.0)]);
+		assert_eq!(mesh.triangle_count(), segments.len() * 2 * resolution as usize + centers.len() * resolution as usize);
+	}
+
+	#[test]
+	fn keeps_frame_continuous_across_bends() {
+		let segments = [
+			segment((0.0, 0.0, 0.0), (0.0, 0.0, 1.0), 0.5),
+			segment((0.0, 0.0, 1.0), (1.0, 0.0, 1.0), 0.5),
+			segment((1.0, 0.0, 1.0), (1.0, 1.0, 1.0), 0.5)
+		];
+		let resolution = 8;
+
+		let mesh = builder(resolution, false).build(&segments);
+		let resolution = resolution as usize;
+		assert_eq!(mesh.vertices.len(), 4 * resolution);
+
+		let centers = [segments[0].begin, segments[0].end, segments[1].end, segments[2].end];
+		let tangents = [
+			Vector3f::z(),
+			(Vector3f::z() + Vector3f::x()).normalize(),
+			(Vector3f::x() + Vector3f::y()).normalize(),
+			Vector3f::y()
+		];
+
+		// Without twist, each ring is the previous ring rotated along with its tangent
+		for i in 0..3 {
+			let rotation = Rotation3::rotation_between(&tangents[i], &tangents[i + 1]).unwrap();
+			let current = radials(&mesh, i * resolution, resolution, centers[i]);
+			let following = radials(&mesh, (i + 1) * resolution, resolution, centers[i + 1]);
+
+			for (a, b) in current.iter().zip(following.iter()) {
+				assert!((rotation * a - b).norm() < 1e-4, "frame twisted between rings {} and {}", i, i + 1);
+			}
+		}
+	}
+
+	#[test]
+	fn interpolates_radii() {
+		let segments = [segment((0.0, 0.0, 0.0), (0.0, 0.0, 1.0), 2.0), segment((0.0, 0.0, 1.0), (0.0, 0.0, 2.0), 1.0)];
+		let resolution = 4;
+
+		let mesh = builder(resolution, false).build(&segments);
+		let resolution = resolution as usize;
+
+		assert_eq!(mesh.vertices.len(), 3 * resolution);
+		assert_radius(&mesh, 0, resolution, segments[0].begin, 1.0);
+		assert_radius(&mesh, resolution, resolution, segments[0].end, 0.5);
+		assert_radius(&mesh, 2 * resolution, resolution, segments[1].end, 0.5);
+
+		// Without interpolation, the radius changes at the joint, which requires separate rings
+		let constant = TubeMeshBuilder { interpolate_width: false, ..builder(resolution as u32, false) }.build(&segments);
+
+		assert_eq!(constant.vertices.len(), 4 * resolution);
+		assert_radius(&constant, resolution, resolution, segments[0].end, 1.0);
+		assert_radius(&constant, 2 * resolution, resolution, segments[1].begin, 0.5);
+	}
+
+	#[test]
+	fn produces_unit_normals() {
+		let segments = [
+			segment((0.0, 0.0, 0.0), (0.0, 0.0, 1.0), 1.0),
+			segment((0.0, 0.0, 1.0), (0.5, 0.3, 1.8), 0.6),
+			segment((0.0, 0.0, 1.0), (-0.4, 0.1, 1.5), 0.3)
+		];
+
+		let mesh = builder(7, true).build(&segments);
+
+		assert_eq!(mesh.normals.len(), mesh.vertices.len());
+		assert!(mesh.normals.iter().all(|n| (n.norm() - 1.0).abs() < 1e-5));
+	}
+}
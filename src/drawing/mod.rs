@@ -1,11 +1,10 @@
 pub mod turtle;
 pub mod primitives;
 pub mod types;
+pub mod custom;
+pub mod mesh;
 
 #[cfg(feature = "serde")]
-use serde::*;
-#[cfg(feature = "serde")]
-#[macro_use]
 use serde_derive::*;
 
 use crate::drawing::types::*;
@@ -39,7 +38,107 @@ pub enum TurtleCommand {
 	IncrementColor = 16,
 	DecrementColor = 17,
 	IncrementLineWidth = 18,
-	DecrementLineWidth = 19
+	DecrementLineWidth = 19,
+
+	/// Set the elasticity of the current branch, which determines how strongly it bends toward the tropism vector
+	SetElasticity = 20,
+
+	/// Roll around the heading so that the left vector becomes horizontal with respect to the world up vector
+	RollToHorizontal = 21,
+
+	/// Move forward without drawing a line and, unlike `ForwardNoDraw`, without recording a vertex inside polygons
+	ForwardNoRecord = 22
+}
+
+impl TurtleCommand {
+	/// Retrieve the maximum number of parameters this command accepts. The parameters have the following meaning:
+	///
+	/// - `Forward`, `ForwardNoDraw`, `ForwardNoRecord`: length, line width, color index
+	/// - `ForwardContracting`: base length, line width, color index
+	/// - Turns, pitches and rolls: angle in degrees, maximum random deviation from that angle in degrees
+	/// - `IncrementColor`, `DecrementColor`: color index to set
+	/// - `IncrementLineWidth`, `DecrementLineWidth`: line width to set
+	/// - `SetElasticity`: elasticity to set. Without parameter, the elasticity is reset to the one in the drawing parameters
+	///
+	/// `Ignore` accepts any number of parameters. All other commands accept a single
+	/// parameter, which is ignored. Line width and color index passed to movement commands are applied to the turtle state before moving, and
+	/// therefore also affect subsequent commands.
+	pub fn max_parameters(&self) -> usize {
+		match self {
+			TurtleCommand::Forward |
+			TurtleCommand::ForwardNoDraw |
+			TurtleCommand::ForwardNoRecord |
+			TurtleCommand::ForwardContracting => 3,
+
+			TurtleCommand::TurnRight |
+			TurtleCommand::TurnLeft |
+			TurtleCommand::PitchDown |
+			TurtleCommand::PitchUp |
+			TurtleCommand::RollLeft |
+			TurtleCommand::RollRight => 2,
+
+			TurtleCommand::IncrementColor |
+			TurtleCommand::DecrementColor |
+			TurtleCommand::IncrementLineWidth |
+			TurtleCommand::DecrementLineWidth |
+			TurtleCommand::SetElasticity => 1,
+
+			TurtleCommand::Ignore => usize::MAX,
+
+			_ => 1
+		}
+	}
+}
+
+/// A drawing parameter that can be referenced by name in the parameter expressions of interpretation mappings,
+/// such as `A(x) => F(x*step)`.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum DrawingVariable {
+	/// `step`
+	Step = 0,
+	/// `angle`, the angle delta
+	Angle = 1,
+	/// `width`, the initial line width
+	LineWidth = 2,
+	/// `width_delta`
+	LineWidthDelta = 3,
+	/// `palette`, the color palette size
+	PaletteSize = 4
+}
+
+impl DrawingVariable {
+	/// All drawing variables, in order of their discriminants.
+	pub const ALL: [DrawingVariable; 5] = [
+		DrawingVariable::Step,
+		DrawingVariable::Angle,
+		DrawingVariable::LineWidth,
+		DrawingVariable::LineWidthDelta,
+		DrawingVariable::PaletteSize
+	];
+
+	/// Retrieve the name used to reference this variable in expressions.
+	pub fn name(&self) -> &'static str {
+		match self {
+			DrawingVariable::Step => "step",
+			DrawingVariable::Angle => "angle",
+			DrawingVariable::LineWidth => "width",
+			DrawingVariable::LineWidthDelta => "width_delta",
+			DrawingVariable::PaletteSize => "palette"
+		}
+	}
+
+	/// Retrieve the value of this variable in given drawing parameters.
+	pub fn value(&self, parameters: &DrawingParameters) -> f64 {
+		match self {
+			DrawingVariable::Step => parameters.step,
+			DrawingVariable::Angle => parameters.angle_delta,
+			DrawingVariable::LineWidth => parameters.initial_line_width,
+			DrawingVariable::LineWidthDelta => parameters.line_width_delta,
+			DrawingVariable::PaletteSize => parameters.color_palette_size as f64
+		}
+	}
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -51,7 +150,21 @@ pub struct DrawingParameters {
 	pub step: f64,
 	pub color_palette_size: u32,
 	pub initial_line_width: f64,
-	pub line_width_delta: f64
+	pub line_width_delta: f64,
+	/// Seed used for random deviations of rotation angles
+	pub random_seed: u64,
+	/// Direction branches bend toward after each forward move, such as gravity or light
+	pub tropism: Vector3d,
+	/// Initial susceptibility of branches to bending toward the tropism vector
+	pub elasticity: f64,
+	/// Vertical direction used when rolling the turtle to horizontal
+	pub world_up: Vector3d
+}
+
+impl Default for DrawingParameters {
+	fn default() -> DrawingParameters {
+		DrawingParameters::new()
+	}
 }
 
 impl DrawingParameters {
@@ -63,7 +176,11 @@ impl DrawingParameters {
 			step: 1.0,
 			color_palette_size: 1,
 			initial_line_width: 1.0,
-			line_width_delta: 0.1
+			line_width_delta: 0.1,
+			random_seed: 0,
+			tropism: Vector3d::zeros(),
+			elasticity: 0.0,
+			world_up: Vector3d::z()
 		}	
 	}
 }
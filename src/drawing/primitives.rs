@@ -1,25 +1,86 @@
 use crate::drawing::types::*;
+#[cfg(feature = "serde")]
+use serde_derive::*;
 
 #[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct LineSegment {
 	pub begin: Vector3f,
 	pub end: Vector3f,
 	pub color: i32,
-	pub width: f32
+	pub width: f32,
+	/// Index of the module that drew this segment
+	pub module_index: usize
 }
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Polygon {
 	pub vertices: Vec<Vector3f>,
-	pub color: i32
+	pub color: i32,
+	/// Index of the module that began this polygon
+	pub module_index: usize
 }
 
 /// Directions on how to spawn a specific bezier patch. Note that this library
 /// does not know anything about this patch - thats the job of the displaying application.
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Patch {
 	/// The identifier of the patch to use
 	pub identifier: char,
 	/// The patches model transformation matrix
-	pub model_transform: Matrix4f
-}
\ No newline at end of file
+	pub model_transform: Matrix4f,
+	/// Index of the module that created this patch
+	pub module_index: usize
+}
+
+/// A sphere, created by modules annotated with `@O`.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Sphere {
+	pub center: Vector3f,
+	pub radius: f32,
+	pub color: i32,
+	/// Index of the module that created this sphere
+	pub module_index: usize
+}
+
+/// A circle, created by modules annotated with `@c`. The circle lies in the plane perpendicular to the
+/// heading of the turtle, which is stored as its normal.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Circle {
+	pub center: Vector3f,
+	pub normal: Vector3f,
+	pub radius: f32,
+	pub color: i32,
+	/// Index of the module that created this circle
+	pub module_index: usize
+}
+
+/// Directions on how to place an instance of an application defined object, created by modules annotated with `@I`.
+/// Like patches, the object itself is not known to this library.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Instance {
+	/// The identifier of the object to instantiate
+	pub identifier: char,
+	/// The instances model transformation matrix
+	pub model_transform: Matrix4f,
+	/// Index of the module that created this instance
+	pub module_index: usize
+}
+
+/// A label placed at a position, created by modules annotated with `@L`.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Label {
+	/// The identifier of the module that created the label
+	pub identifier: char,
+	pub position: Vector3f,
+	/// The parameter values of the module that created the label
+	pub values: Vec<f64>,
+	/// Index of the module that created this label
+	pub module_index: usize
+}
@@ -1,21 +1,42 @@
 use nalgebra::*;
+use rand::*;
+use rand_chacha::ChaCha20Rng;
 
 use crate::drawing::*;
 use crate::drawing::primitives::*;
 use crate::drawing::types::*;
+use crate::drawing::custom::*;
+use crate::drawing::mesh::*;
 use crate::interpretation::DrawingCommand;
+#[cfg(feature = "serde")]
+use serde_derive::*;
 
 
 /// A structure containing all the primitves that were created from a 
 /// sequence of drawing commands during a drawing run.
 #[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct DrawingResult {
 	/// Polygons directly created by commands such as SubmitVertex.
 	pub polygons: Vec<Polygon>,
 	/// Line segments created by moving the turtle.
 	pub line_segments: Vec<LineSegment>,
 	/// Patch rendering instructions
-	pub patches: Vec<Patch>
+	pub patches: Vec<Patch>,
+	/// Spheres created by annotated modules
+	pub spheres: Vec<Sphere>,
+	/// Circles created by annotated modules
+	pub circles: Vec<Circle>,
+	/// Object instantiation instructions
+	pub instances: Vec<Instance>,
+	/// Labels created by annotated modules
+	pub labels: Vec<Label>
+}
+
+impl Default for DrawingResult {
+	fn default() -> DrawingResult {
+		DrawingResult::new()
+	}
 }
 
 impl DrawingResult {
@@ -23,9 +44,24 @@ impl DrawingResult {
 		DrawingResult {
 			polygons: Vec::new(),
 			line_segments: Vec::new(),
-			patches: Vec::new()
+			patches: Vec::new(),
+			spheres: Vec::new(),
+			circles: Vec::new(),
+			instances: Vec::new(),
+			labels: Vec::new()
 		}
 	}
+
+	/// Triangulate all polygons into a single indexed triangle mesh. See `mesh::triangulate`.
+	pub fn polygon_mesh(&self) -> TriangleMesh {
+		let mut mesh = TriangleMesh::new();
+
+		for polygon in &self.polygons {
+			mesh.append(&triangulate(polygon));
+		}
+
+		mesh
+	}
 }
 
 struct Turtle3DMatrixCache {
@@ -76,29 +112,33 @@ impl Turtle3DMatrixCache {
 	}
 }
 
+/// The state of the turtle, which is saved and restored by the state stack commands.
 #[derive(Clone, Copy, Debug)]
-struct Turtle3DState {
-	position: Vector3d,
-	heading: Vector3dU,
-	left: Vector3dU,
-	up: Vector3dU,
-	color_index: i32,
-	line_width: f64
+pub struct Turtle3DState {
+	pub position: Vector3d,
+	pub heading: Vector3dU,
+	pub left: Vector3dU,
+	pub up: Vector3dU,
+	pub color_index: i32,
+	pub line_width: f64,
+	/// Susceptibility of the current branch to bending toward the tropism vector
+	pub elasticity: f64
 }
 
 impl Turtle3DState {
-	fn new(start_position: Vector3d, start_angle: f64, initial_line_width: f64) -> Turtle3DState {
+	fn new(start_position: Vector3d, start_angle: f64, initial_line_width: f64, elasticity: f64) -> Turtle3DState {
 		let up = Vector3d::z_axis();
 		let heading = Vector3dU::new_unchecked(Vector3d::new(start_angle.cos(), start_angle.sin(), 0.0));
 		let left = Vector3dU::new_normalize(up.cross(&heading));
 
 		return Turtle3DState {
-			up: up,
-			heading: heading,
-			left: left,
+			up,
+			heading,
+			left,
 			position: start_position,
 			color_index: 0,
-			line_width: initial_line_width
+			line_width: initial_line_width,
+			elasticity
 		};
 	}
 }
@@ -109,47 +149,152 @@ pub struct Turtle3D {
 	matrix_cache: Turtle3DMatrixCache,
 	current_state: Turtle3DState,
 	state_stack: Vec<Turtle3DState>,
-	current_polygon: Vec<Vector3f>,
+	/// Polygons that were begun but not yet ended, innermost last. Their color is set when they are ended
+	polygon_stack: Vec<Polygon>,
 	drawing_result: DrawingResult,
-	num_iterations: u32
+	num_iterations: u32,
+	/// Random number generator used for random deviations of rotation angles
+	rng: ChaCha20Rng,
+	/// Operations implemented by the application
+	custom_commands: CustomCommandRegistry,
+	/// Index of the module the currently executed command was derived from
+	module_index: usize
 }
 
 impl Turtle3D {
 	pub fn new(draw_parameters: DrawingParameters, num_iterations: u32) -> Turtle3D {
 		return Turtle3D {
-			draw_parameters: draw_parameters,
+			draw_parameters,
 			contracted_length: draw_parameters.step.powf(num_iterations as f64),
 			state_stack: Vec::new(),
 			matrix_cache: Turtle3DMatrixCache::new(draw_parameters.angle_delta),
-			current_polygon: Vec::new(),
+			polygon_stack: Vec::new(),
 			drawing_result: DrawingResult::new(),
-			num_iterations: num_iterations,
+			num_iterations,
+			rng: ChaCha20Rng::seed_from_u64(draw_parameters.random_seed),
+			custom_commands: CustomCommandRegistry::new(),
+			module_index: 0,
 			current_state: Turtle3DState::new(
 				Vector3d::new(draw_parameters.start_position.x as f64, draw_parameters.start_position.y as f64, 0.0),
 				draw_parameters.start_angle,
-				draw_parameters.initial_line_width
+				draw_parameters.initial_line_width,
+				draw_parameters.elasticity
 			)
 		}	
 	}
 
+	/// Set the registry used to execute `DrawingCommand::CustomCommand` commands. Custom commands whose id is not
+	/// registered are ignored.
+	pub fn set_custom_commands(&mut self, commands: CustomCommandRegistry) {
+		self.custom_commands = commands;
+	}
+
+	/// Take the registry of custom commands out of this turtle, leaving an empty one.
+	pub fn take_custom_commands(&mut self) -> CustomCommandRegistry {
+		std::mem::take(&mut self.custom_commands)
+	}
+
+	/// Retrieve the current turtle state.
+	pub fn state(&self) -> &Turtle3DState {
+		&self.current_state
+	}
+
 	fn is_polygon_active(&self) -> bool {
-		return !self.current_polygon.is_empty();
+		return !self.polygon_stack.is_empty();
 	}
 
+	/// Record the current position as vertex of the innermost polygon. Vertices outside of polygons are ignored, as
+	/// are vertices that coincide with the previously recorded one, which happens when a move that records a vertex
+	/// is followed by an explicit `SubmitVertex`.
 	fn submit_vertex(&mut self) {
-		self.current_polygon.push(Self::convert_vector(&self.current_state.position));
+		let vertex = Self::convert_vector(&self.current_state.position);
+
+		if let Some(polygon) = self.polygon_stack.last_mut() {
+			if(polygon.vertices.last() != Some(&vertex)) {
+				polygon.vertices.push(vertex);
+			}
+		}
 	}
 
 	fn convert_vector(vec: &Vector3d) -> Vector3f {
 		Vector3f::new(vec.x as _, vec.y as _, vec.z as _)
 	}
 
+	/// Begin a new polygon. Polygons can be nested, in which case the inner polygon is separate from the outer one.
 	fn begin_polygon(&mut self) {
-		// Nothing to do
+		self.polygon_stack.push(Polygon {
+			vertices: Vec::new(),
+			color: self.current_state.color_index,
+			module_index: self.module_index
+		});
 	}
 
 	/// Determine bezier patch orientation and save to result
 	fn create_patch(&mut self, identifier: char, scale: f64) {
+		let model_transform = self.model_transform(scale);
+
+		self.drawing_result.patches.push(
+			Patch {
+				model_transform,
+				identifier,
+				module_index: self.module_index
+			}
+		)
+	}
+
+	/// Determine object instance orientation and save to result
+	fn create_instance(&mut self, identifier: char, scale: f64) {
+		let model_transform = self.model_transform(scale);
+
+		self.drawing_result.instances.push(
+			Instance {
+				model_transform,
+				identifier,
+				module_index: self.module_index
+			}
+		)
+	}
+
+	fn create_sphere(&mut self, radius: Option<f64>) {
+		let radius = radius.unwrap_or(self.current_state.line_width / 2.0);
+
+		self.drawing_result.spheres.push(
+			Sphere {
+				center: Self::convert_vector(&self.current_state.position),
+				radius: radius as _,
+				color: self.current_state.color_index,
+				module_index: self.module_index
+			}
+		)
+	}
+
+	fn create_circle(&mut self, radius: Option<f64>) {
+		let radius = radius.unwrap_or(self.current_state.line_width / 2.0);
+
+		self.drawing_result.circles.push(
+			Circle {
+				center: Self::convert_vector(&self.current_state.position),
+				normal: Self::convert_vector(&self.current_state.heading.into_inner()),
+				radius: radius as _,
+				color: self.current_state.color_index,
+				module_index: self.module_index
+			}
+		)
+	}
+
+	fn create_label(&mut self, identifier: char, values: &[f64]) {
+		self.drawing_result.labels.push(
+			Label {
+				identifier,
+				position: Self::convert_vector(&self.current_state.position),
+				values: values.to_vec(),
+				module_index: self.module_index
+			}
+		)
+	}
+
+	/// Build a model transformation matrix placing objects at the turtle position, oriented along the turtle axes
+	fn model_transform(&self, scale: f64) -> Matrix4f {
 		// The model transformation matrix contains both a base change matrix as well as
 		// a translation component.
 		// The goal is to achieve the following transformations: 
@@ -172,27 +317,38 @@ impl Turtle3D {
 
 		let scaling = Matrix4f::new_scaling(scale as _);
 
-		self.drawing_result.patches.push(
-			Patch {
-				model_transform: model_matrix * scaling,
-				identifier: identifier
-			}
-		)
+		model_matrix * scaling
 	}
 
 	pub fn retrieve_result(&self) -> &DrawingResult {
 		&self.drawing_result
 	}
 
+	/// End the innermost polygon and add it to the result, using the current color. Polygons without vertices are
+	/// discarded.
 	fn end_polygon(&mut self) {
-		self.drawing_result.polygons.push(
-			Polygon {
-				vertices: self.current_polygon.clone(),
-				color: self.current_state.color_index		
+		if let Some(mut polygon) = self.polygon_stack.pop() {
+			if(!polygon.vertices.is_empty()) {
+				polygon.color = self.current_state.color_index;
+				self.drawing_result.polygons.push(polygon);
 			}
-		);
+		}
+	}
+
+	/// Move forward like `move_forward`. Inside a polygon, the start and end positions are recorded as vertices if
+	/// requested, which allows tracing polygon outlines with moves.
+	fn move_in_polygon(&mut self, distance: f64, draw: bool, record: bool) {
+		let record = record && self.is_polygon_active();
+
+		if(record) {
+			self.submit_vertex();
+		}
 
-		self.current_polygon.clear();
+		self.move_forward(distance, draw);
+
+		if(record) {
+			self.submit_vertex();
+		}
 	}
 
 	fn apply_rotation(&mut self, matrix: Matrix3d) {
@@ -213,64 +369,166 @@ impl Turtle3D {
 		self.current_state.line_width = (self.current_state.line_width + delta).max(0.0);	
 	}
 
+	/// Execute all given drawing commands, in order.
 	pub fn execute_modules(&mut self, commands: &[DrawingCommand]) {
 		for command in commands {
-			match command {
-				// Patch creation
-				DrawingCommand::SpawnPatch{patch_id, scaling} => self.create_patch(*patch_id, *scaling),
-
-				// Moving
-				DrawingCommand::BasicCommand{operation: TurtleCommand::Forward, parameter: p} => self.move_forward(p.unwrap_or(self.draw_parameters.step), true),
-				DrawingCommand::BasicCommand{operation: TurtleCommand::ForwardNoDraw, parameter: p} => self.move_forward(p.unwrap_or(self.draw_parameters.step), false),
-				DrawingCommand::BasicCommand{operation: TurtleCommand::ForwardContracting, parameter: p} => {
-					let distance = if(p.is_some()) {
-						p.unwrap().powf(self.num_iterations as f64)
-					} else {
-						self.contracted_length
-					};
-
-					self.move_forward(distance, true);
-				},
-
-				// State handling
-				DrawingCommand::BasicCommand{operation: TurtleCommand::SaveState, ..} => self.push_state(),
-				DrawingCommand::BasicCommand{operation: TurtleCommand::LoadState, ..} => self.pop_state(),
-
-				// Direction changes
-				DrawingCommand::BasicCommand{operation: TurtleCommand::TurnLeft, parameter: p} =>  self.apply_rotation(self.rotU(p, self.matrix_cache.turn_left)),
-				DrawingCommand::BasicCommand{operation: TurtleCommand::TurnRight, parameter: p} =>  self.apply_rotation(self.rotUInv(p, self.matrix_cache.turn_right)),
-				DrawingCommand::BasicCommand{operation: TurtleCommand::PitchDown, parameter: p} =>  self.apply_rotation(self.rotL(p, self.matrix_cache.pitch_down)),
-				DrawingCommand::BasicCommand{operation: TurtleCommand::PitchUp, parameter: p} =>  self.apply_rotation(self.rotLInv(p, self.matrix_cache.pitch_up)),
-				DrawingCommand::BasicCommand{operation: TurtleCommand::RollLeft, parameter: p} =>  self.apply_rotation(self.rotH(p, self.matrix_cache.roll_left)),
-				DrawingCommand::BasicCommand{operation: TurtleCommand::RollRight, parameter: p} =>  self.apply_rotation(self.rotHInv(p, self.matrix_cache.roll_right)),
-				DrawingCommand::BasicCommand{operation: TurtleCommand::TurnAround, parameter: p} =>  self.apply_rotation(self.matrix_cache.turn_around),
-				
-				// Polygon handling
-				DrawingCommand::BasicCommand{operation: TurtleCommand::BeginPolygon, ..} => self.begin_polygon(),
-				DrawingCommand::BasicCommand{operation: TurtleCommand::EndPolygon, ..} => self.end_polygon(),
-				DrawingCommand::BasicCommand{operation: TurtleCommand::SubmitVertex, ..} => self.submit_vertex(),
-
-				// Color handling
-				DrawingCommand::BasicCommand{operation: TurtleCommand::IncrementColor, parameter: None} => self.modify_color_index(1),
-				DrawingCommand::BasicCommand{operation: TurtleCommand::DecrementColor, parameter: None} => self.modify_color_index(-1),
-
-				DrawingCommand::BasicCommand{operation: TurtleCommand::IncrementColor, parameter: Some(p)} => self.current_state.color_index = (*p as i32).min(self.draw_parameters.color_palette_size as i32 - 1).max(0),
-				DrawingCommand::BasicCommand{operation: TurtleCommand::DecrementColor, parameter: Some(p)} => self.current_state.color_index = (*p as i32).min(self.draw_parameters.color_palette_size as i32 - 1).max(0),
-
-				// Line width handling
-				// If no parameter is given, the line width commands increment or decrement the line width by the line width delta value.
-				DrawingCommand::BasicCommand{operation: TurtleCommand::IncrementLineWidth, parameter: None} => self.modify_line_width(self.draw_parameters.line_width_delta),
-				DrawingCommand::BasicCommand{operation: TurtleCommand::DecrementLineWidth, parameter: None} => self.modify_line_width(-self.draw_parameters.line_width_delta),
-
-				// If a parameter is given, they set the line width to that parameters value.
-				DrawingCommand::BasicCommand{operation: TurtleCommand::IncrementLineWidth, parameter: Some(p)} => self.current_state.line_width = p.max(0.0),
-				DrawingCommand::BasicCommand{operation: TurtleCommand::DecrementLineWidth, parameter: Some(p)} => self.current_state.line_width = p.max(0.0),
-
-				_ => ()
+			self.execute_command(command);
+		}
+	}
+
+	/// Execute a single drawing command.
+	pub fn execute_command(&mut self, command: &DrawingCommand) {
+		self.module_index = command.module_index();
+
+		match command {
+			// Patch creation
+			DrawingCommand::SpawnPatch{patch_id, scaling, ..} => self.create_patch(*patch_id, *scaling),
+			// Other annotated modules
+			DrawingCommand::SpawnSphere{radius, ..} => self.create_sphere(*radius),
+			DrawingCommand::SpawnCircle{radius, ..} => self.create_circle(*radius),
+			DrawingCommand::SpawnInstance{identifier, scaling, ..} => self.create_instance(*identifier, *scaling),
+			DrawingCommand::SpawnLabel{identifier, values, ..} => self.create_label(*identifier, values),
+			DrawingCommand::BasicCommand{operation, parameters, ..} => self.execute_operation(*operation, parameters),
+			DrawingCommand::CustomCommand{id, parameters, ..} => self.execute_custom(*id, parameters)
+		}
+	}
+
+	/// Execute given turtle command with given parameters. See `TurtleCommand::max_parameters` for the
+	/// meaning of the parameters. Superfluous parameters are ignored.
+	fn execute_operation(&mut self, operation: TurtleCommand, parameters: &[f64]) {
+		let p = parameters.first().cloned();
+
+		match operation {
+			// Moving
+			TurtleCommand::Forward => {
+				self.apply_segment_attributes(parameters);
+				self.move_in_polygon(p.unwrap_or(self.draw_parameters.step), true, true);
+			},
+			TurtleCommand::ForwardNoDraw => {
+				self.apply_segment_attributes(parameters);
+				self.move_in_polygon(p.unwrap_or(self.draw_parameters.step), false, true);
+			},
+			TurtleCommand::ForwardNoRecord => {
+				self.apply_segment_attributes(parameters);
+				self.move_forward(p.unwrap_or(self.draw_parameters.step), false);
+			},
+			TurtleCommand::ForwardContracting => {
+				let distance = match p {
+					Some(p) => p.powf(self.num_iterations as f64),
+					None => self.contracted_length
+				};
+
+				self.apply_segment_attributes(parameters);
+				self.move_in_polygon(distance, true, true);
+			},
+
+			// State handling
+			TurtleCommand::SaveState => self.push_state(),
+			TurtleCommand::LoadState => self.pop_state(),
+
+			// Direction changes
+			TurtleCommand::TurnLeft => {
+				let angle = self.rotation_angle(parameters);
+				self.apply_rotation(self.rotU(&angle, self.matrix_cache.turn_left));
+			},
+			TurtleCommand::TurnRight => {
+				let angle = self.rotation_angle(parameters);
+				self.apply_rotation(self.rotUInv(&angle, self.matrix_cache.turn_right));
+			},
+			TurtleCommand::PitchDown => {
+				let angle = self.rotation_angle(parameters);
+				self.apply_rotation(self.rotL(&angle, self.matrix_cache.pitch_down));
+			},
+			TurtleCommand::PitchUp => {
+				let angle = self.rotation_angle(parameters);
+				self.apply_rotation(self.rotLInv(&angle, self.matrix_cache.pitch_up));
+			},
+			TurtleCommand::RollLeft => {
+				let angle = self.rotation_angle(parameters);
+				self.apply_rotation(self.rotH(&angle, self.matrix_cache.roll_left));
+			},
+			TurtleCommand::RollRight => {
+				let angle = self.rotation_angle(parameters);
+				self.apply_rotation(self.rotHInv(&angle, self.matrix_cache.roll_right));
+			},
+			TurtleCommand::TurnAround => self.apply_rotation(self.matrix_cache.turn_around),
+			TurtleCommand::RollToHorizontal => self.roll_to_horizontal(),
+
+			// Polygon handling
+			TurtleCommand::BeginPolygon => self.begin_polygon(),
+			TurtleCommand::EndPolygon => self.end_polygon(),
+			TurtleCommand::SubmitVertex => self.submit_vertex(),
+
+			// Color handling
+			// If a parameter is given, the color commands set the color index to that parameters value.
+			TurtleCommand::IncrementColor => match p {
+				None => self.modify_color_index(1),
+				Some(p) => self.set_color_index(p)
+			},
+			TurtleCommand::DecrementColor => match p {
+				None => self.modify_color_index(-1),
+				Some(p) => self.set_color_index(p)
+			},
+
+			// Line width handling
+			// If no parameter is given, the line width commands increment or decrement the line width by the line width delta value.
+			// If a parameter is given, they set the line width to that parameters value.
+			TurtleCommand::IncrementLineWidth => match p {
+				None => self.modify_line_width(self.draw_parameters.line_width_delta),
+				Some(p) => self.current_state.line_width = p.max(0.0)
+			},
+			TurtleCommand::DecrementLineWidth => match p {
+				None => self.modify_line_width(-self.draw_parameters.line_width_delta),
+				Some(p) => self.current_state.line_width = p.max(0.0)
+			},
+
+			// Tropism handling
+			TurtleCommand::SetElasticity => self.current_state.elasticity = p.unwrap_or(self.draw_parameters.elasticity),
+
+			TurtleCommand::Ignore => ()
+		}
+	}
+
+	/// Execute the custom command registered under given id, if any.
+	fn execute_custom(&mut self, id: u32, parameters: &[f64]) {
+		if let Some(command) = self.custom_commands.get_mut(id) {
+			command.execute(&mut self.current_state, parameters, self.module_index, &mut self.drawing_result);
+		}
+	}
+
+	/// Apply the optional line width and color index parameters of a movement command.
+	fn apply_segment_attributes(&mut self, parameters: &[f64]) {
+		if let Some(width) = parameters.get(1) {
+			self.current_state.line_width = width.max(0.0);
+		}
+
+		if let Some(color) = parameters.get(2) {
+			self.set_color_index(*color);
+		}
+	}
+
+	/// Determine the angle of a rotation command, in degrees, from its parameters. If a second parameter is given,
+	/// the angle is randomly deviated by at most that amount.
+	fn rotation_angle(&mut self, parameters: &[f64]) -> Option<f64> {
+		match parameters {
+			[] => None,
+			[angle] => Some(*angle),
+			[angle, deviation, ..] => {
+				let deviation = deviation.abs();
+
+				if(deviation > 0.0) {
+					Some(angle + self.rng.gen_range(-deviation, deviation))
+				} else {
+					Some(*angle)
+				}
 			}
 		}
 	}
 
+	fn set_color_index(&mut self, value: f64) {
+		self.current_state.color_index = (value as i32).min(self.draw_parameters.color_palette_size as i32 - 1).max(0);
+	}
+
 	fn rotU(&self, param: &Option<f64>, def: Matrix3d) -> Matrix3d {
 		self.matrix_helper(Turtle3DMatrixCache::rotU, param, def, 1.0)
 	}
@@ -307,12 +565,44 @@ impl Turtle3D {
 		self.current_state.color_index = clamp(self.current_state.color_index + value, 0, (self.draw_parameters.color_palette_size - 1) as i32);
 	}
 
+	/// Roll the turtle around its heading H such that the left vector L becomes horizontal, by setting
+	/// L = (V × H) / |V × H| and U = H × L, where V is the world up vector. Nothing happens if H is parallel to V.
+	fn roll_to_horizontal(&mut self) {
+		let heading = self.current_state.heading.into_inner();
+		let left = self.draw_parameters.world_up.cross(&heading);
+
+		if(left.norm() <= f64::EPSILON) {
+			return;
+		}
+
+		self.current_state.left = Vector3dU::new_normalize(left);
+		self.current_state.up = Vector3dU::new_normalize(heading.cross(&left));
+	}
+
+	/// Bend the heading toward the tropism vector T. The turtle is rotated around H × T by an angle of e·|H × T|,
+	/// where e is the elasticity of the current branch.
+	fn apply_tropism(&mut self) {
+		let axis = self.current_state.heading.cross(&self.draw_parameters.tropism);
+		let angle = self.current_state.elasticity * axis.norm();
+
+		if(angle == 0.0) {
+			return;
+		}
+
+		let rotation = Rotation3::from_axis_angle(&Vector3dU::new_normalize(axis), angle);
+
+		self.current_state.heading = Vector3dU::new_normalize(rotation * self.current_state.heading.into_inner());
+		self.current_state.left = Vector3dU::new_normalize(rotation * self.current_state.left.into_inner());
+		self.current_state.up = Vector3dU::new_normalize(rotation * self.current_state.up.into_inner());
+	}
+
 	pub fn move_forward(&mut self, distance: f64, draw: bool) {
 		let old_position = self.current_state.position;
 		
 		let mv = self.current_state.heading.into_inner() * distance;
 
 		self.current_state.position = old_position + mv;
+		self.apply_tropism();
 
 		if draw {
 			let begin = Vector3f::new(
@@ -328,21 +618,22 @@ impl Turtle3D {
 			);
 
 			self.drawing_result.line_segments.push(LineSegment{
-				begin: begin,
-				end: end,
+				begin,
+				end,
 				color: self.current_state.color_index,
-				width: self.current_state.line_width as _
+				width: self.current_state.line_width as _,
+				module_index: self.module_index
 			});		
 		}
 	}
 
 	pub fn push_state(&mut self) {
-		self.state_stack.push(self.current_state.clone())
+		self.state_stack.push(self.current_state)
 	}
 
 	pub fn pop_state(&mut self) {
-		if(self.state_stack.len() > 0) {
-			self.current_state = self.state_stack.last().unwrap().clone();
+		if(!self.state_stack.is_empty()) {
+			self.current_state = *self.state_stack.last().unwrap();
 			self.state_stack.pop();
 		}
 	}
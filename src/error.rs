@@ -0,0 +1,59 @@
+use std::fmt;
+use crate::limits::Limit;
+use crate::drawing::DrawingVariable;
+
+/// Errors that can occur while iterating or interpreting an L-System. Module indices refer to the position
+/// of the offending module in the module string that was being processed, rules are identified by their index
+/// in the rule list of the iteration engine.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LSystemError {
+	/// A rule references a parameter that is not bound by its pattern.
+	UnboundParameter { parameter: char, module_index: usize, rule: usize },
+	/// A rule pattern binds the same parameter name more than once.
+	DuplicateParameter { parameter: char, module_index: usize, rule: usize },
+	/// A rule references a drawing variable, which is only available in interpretation mappings.
+	UnboundVariable { variable: DrawingVariable, module_index: usize, rule: usize },
+	/// Only stochastic rules matched a module, and their weights sum up to zero.
+	ZeroTotalWeight { module_index: usize, rules: Vec<usize> },
+	/// A module has more parameters than its interpretation is able to consume.
+	TooManyParameters { identifier: char, count: usize, accepted: usize, module_index: usize },
+	/// A module has fewer parameters than its interpretation requires.
+	MissingParameters { identifier: char, count: usize, expected: usize, module_index: usize },
+	/// A module has no interpretation, and the unmapped symbol policy of the interpretation engine is `UnmappedPolicy::Error`.
+	UnmappedSymbol { identifier: char, module_index: usize },
+	/// Lazy derivation was requested, but the given rule depends on the context of the modules it matches.
+	ContextSensitiveRule { rule: usize },
+	/// Deriving the given generation exceeded a resource limit.
+	LimitExceeded { limit: Limit, generation: u32 },
+	/// Iteration was cancelled while deriving the given generation.
+	Cancelled { generation: u32 }
+}
+
+impl fmt::Display for LSystemError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			LSystemError::UnboundParameter{parameter, module_index, rule} =>
+				write!(f, "rule {} references unbound parameter '{}' (module {})", rule, parameter, module_index),
+			LSystemError::DuplicateParameter{parameter, module_index, rule} =>
+				write!(f, "rule {} binds parameter '{}' more than once (module {})", rule, parameter, module_index),
+			LSystemError::UnboundVariable{variable, module_index, rule} =>
+				write!(f, "rule {} references drawing variable '{}' (module {})", rule, variable.name(), module_index),
+			LSystemError::ZeroTotalWeight{module_index, rules} =>
+				write!(f, "stochastic rules {:?} matching module {} have a total weight of zero", rules, module_index),
+			LSystemError::TooManyParameters{identifier, count, accepted, module_index} =>
+				write!(f, "module '{}' at index {} has {} parameters, but its interpretation accepts at most {}", identifier, module_index, count, accepted),
+			LSystemError::MissingParameters{identifier, count, expected, module_index} =>
+				write!(f, "module '{}' at index {} has {} parameters, but its interpretation requires {}", identifier, module_index, count, expected),
+			LSystemError::ContextSensitiveRule{rule} =>
+				write!(f, "rule {} is context-sensitive, which is not supported by lazy derivation", rule),
+			LSystemError::LimitExceeded{limit, generation} =>
+				write!(f, "{} limit exceeded while deriving generation {}", limit, generation),
+			LSystemError::UnmappedSymbol{identifier, module_index} =>
+				write!(f, "module '{}' has no interpretation (module {})", identifier, module_index),
+			LSystemError::Cancelled{generation} =>
+				write!(f, "iteration cancelled while deriving generation {}", generation)
+		}
+	}
+}
+
+impl std::error::Error for LSystemError {}
@@ -1,12 +1,38 @@
-use peg::parser;
+use peg::{parser, RuleResult};
 use crate::iteration::*;
+use crate::annotation::*;
+use crate::drawing::DrawingVariable;
+
+/// Matching of annotation prefixes, which are not known when the grammar is generated since applications can register
+/// their own.
+trait ParseAnnotation {
+    fn annotation_prefix(&self, pos: usize, annotations: &AnnotationRegistry) -> RuleResult<ModuleAnnotation>;
+}
+
+impl ParseAnnotation for str {
+    fn annotation_prefix(&self, pos: usize, annotations: &AnnotationRegistry) -> RuleResult<ModuleAnnotation> {
+        match annotations.match_annotation(&self[pos..]) {
+            Some((annotation, length)) => RuleResult::Matched(pos + length, annotation),
+            None => RuleResult::Failed
+        }
+    }
+}
+
+/// Characters other than ASCII letters and digits that can be used as module identifiers.
+const IDENTIFIER_SYMBOLS: &str = "!^+'-[]\\/|#&{}.$";
+
+/// Check whether given character can be used as a module identifier.
+pub(crate) fn is_identifier_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || IDENTIFIER_SYMBOLS.contains(c)
+}
 
 fn boxed<T>(t: T) -> Box<T> {
     Box::new(t)
 }
 
 parser!{
-	pub grammar lsystem_parser() for str {
+	/// Grammar of module strings, rules and mappings. Annotations are recognized using given registry.
+	pub grammar lsystem_parser(annotations: &AnnotationRegistry) for str {
 		rule true_false() -> bool
 			= b:$("true" / "false") { b.parse().unwrap() }
 			/ "*" { true }
@@ -31,6 +57,10 @@ parser!{
 			}
 
 		rule arith_expr() -> ArithmeticExpression
+			= arith_expr_in(false)
+
+		/// Arithmetic expression, which may reference drawing variables if `variables` is set.
+		rule arith_expr_in(variables: bool) -> ArithmeticExpression
 			= precedence!{
 				l:(@) padding() "+" padding() r:@ { ArithmeticExpression::Add(boxed(l), boxed(r)) }
 				l:(@) padding() "-" padding() r:@ { ArithmeticExpression::Sub(boxed(l), boxed(r)) }
@@ -43,9 +73,18 @@ parser!{
 				"-" r:(@) { ArithmeticExpression::Neg(boxed(r)) }
 				--
 				n:number() { ArithmeticExpression::Const(n) }
+				v:variable(variables) { ArithmeticExpression::Variable(v) }
 				p:parameter_name() { ArithmeticExpression::Param(p) }
 			}
 
+		rule variable(variables: bool) -> DrawingVariable
+			= name:$(['a'..='z' | '_']+) {?
+				match DrawingVariable::ALL.iter().find(|v| v.name() == name) {
+					Some(v) if variables => Ok(*v),
+					_ => Err("drawing variable")
+				}
+			}
+
 		rule whitespace()
 			= quiet!{[' ' | '\t']+}
 
@@ -59,7 +98,7 @@ parser!{
 			= n:$(['+'|'-']?['0'..='9']+("." ['0'..='9']+)?) { n.parse().unwrap() }
 
 		rule identifier() -> char
-			= x:$(['a'..='z' | 'A'..='Z' | '0'..='9' | '!' | '^' | '+' | '\'' | '-' | '[' | ']' | '\\' | '/' | '|' | '#' | '&' | '{' | '}' | '.']) { x.parse().unwrap() }
+			= x:$([c if is_identifier_char(c)]) { x.parse().unwrap() }
 
 		rule condition() -> BooleanExpression
 			= expr:(condition_part())? { expr.unwrap_or(BooleanExpression::Const(true)) }
@@ -76,7 +115,7 @@ parser!{
 		rule signature_with_parameters() -> ModuleSignature
 			= a:optional_annotation() x:identifier() "(" padding() p:parameter_name() ** (padding() "," padding())  padding() ")" { ModuleSignature{ identifier: x, parameters: p, annotation: a } }
 
-		rule signature() -> ModuleSignature
+		pub rule signature() -> ModuleSignature
 			= signature_with_parameters() / simple_signature()
 
 		rule left_pattern() -> ModuleSignature
@@ -100,9 +139,30 @@ parser!{
 		rule template_string_entry() -> ModuleTemplate
 			= padding() t:template() padding() { t }
 
-		rule template_string() -> Vec<ModuleTemplate>
+		pub rule template_string() -> Vec<ModuleTemplate>
 			= template_string_entry()*
 
+		rule mapping_template() -> ModuleTemplate
+			= a:optional_annotation() x:identifier() "(" expr:arith_expr_in(true) ** ","  ")" { ModuleTemplate{ identifier: x, parameter_expressions: expr, annotation: a } }
+			/ simple_template()
+
+		rule mapping_template_string_entry() -> ModuleTemplate
+			= padding() t:mapping_template() padding() { t }
+
+		/// A template string whose parameter expressions may reference drawing variables.
+		pub rule mapping_template_string() -> Vec<ModuleTemplate>
+			= mapping_template_string_entry()*
+
+		/// An interpretation mapping, such as "A(x,y) => F(x*step) +(y*10)".
+		pub rule mapping() -> (ModuleSignature, Vec<ModuleTemplate>)
+			= padding() s:signature() padding() "=>" t:mapping_template_string() { (s, t) }
+
+		rule mapping_list_inner() -> Vec<(ModuleSignature, Vec<ModuleTemplate>)>
+			= ms:mapping() ** rule_list_newline() { ms }
+
+		pub rule mapping_list() -> Vec<(ModuleSignature, Vec<ModuleTemplate>)>
+			= rule_list_newline() ms:mapping_list_inner() rule_list_newline() { ms }
+
 		rule probability() -> f64
 			= ":" padding() p:number() { p }
 
@@ -133,11 +193,8 @@ parser!{
 		rule rule_list_inner() -> Vec<Rule>
 			= rs:lsystem_rule() ** rule_list_newline() { rs }
 
-		rule annotation_create_patch() -> ModuleAnnotation
-			= "~" { ModuleAnnotation::CreatePatch }
-
 		rule annotation() -> ModuleAnnotation
-			= annotation_create_patch() /* / .. / .. */
+			= quiet!{##annotation_prefix(annotations)} / expected!("annotation")
 
 		rule optional_annotation() -> Option<ModuleAnnotation>
 			= (annotation())?
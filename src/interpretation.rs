@@ -1,101 +1,721 @@
-use std::collections::HashMap;
-use crate::drawing::TurtleCommand;
+use std::collections::{HashMap, BTreeMap};
+use std::fmt;
+use std::convert::TryFrom;
+use crate::drawing::{TurtleCommand, DrawingParameters, DrawingVariable};
 use crate::iteration::*;
+use crate::error::*;
+use crate::module_string::*;
+use crate::compiler::*;
+use crate::grammar;
+use crate::validation::Diagnostic;
+use crate::annotation::*;
+#[cfg(feature = "serde")]
+use serde_derive::*;
+
+/// Errors that can occur while defining interpretation mappings.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MappingError {
+    /// The textual representation of a mapping could not be parsed.
+    Syntax(String),
+    /// A symbol used in the expansion of a mapping is not associated with a turtle command.
+    UnknownSymbol(char),
+    /// A parameter expression references an unbound parameter, or a parameter name is bound more than once.
+    Evaluation(EvaluationError),
+    /// A command in the expansion of a mapping is passed more parameters than it accepts.
+    TooManyParameters { operation: TurtleCommand, count: usize, accepted: usize },
+    /// A module of a mapping carries an annotation. Macros are associated with plain identifiers and can only
+    /// expand to turtle commands, which do not carry annotations.
+    Annotation(char)
+}
+
+impl fmt::Display for MappingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MappingError::Syntax(e) => write!(f, "syntax error: {}", e),
+            MappingError::UnknownSymbol(c) => write!(f, "symbol '{}' is not associated with a turtle command", c),
+            MappingError::Evaluation(EvaluationError::UnboundParameter(p)) => write!(f, "parameter '{}' is not bound", p),
+            MappingError::Evaluation(EvaluationError::DuplicateParameter(p)) => write!(f, "parameter '{}' is bound more than once", p),
+            MappingError::Evaluation(EvaluationError::UnboundVariable(v)) => write!(f, "drawing variable '{}' is not available", v.name()),
+            MappingError::TooManyParameters{operation, count, accepted} =>
+                write!(f, "{:?} is passed {} parameters, but accepts at most {}", operation, count, accepted),
+            MappingError::Annotation(c) => write!(f, "symbol '{}' carries an annotation, which mappings do not support", c)
+        }
+    }
+}
+
+impl std::error::Error for MappingError {}
+
+impl From<EvaluationError> for MappingError {
+    fn from(e: EvaluationError) -> MappingError {
+        MappingError::Evaluation(e)
+    }
+}
+
+/// A turtle command whose parameters are given as expressions, used in command macros.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct CommandTemplate {
+    pub operation: TurtleCommand,
+    pub parameters: Vec<ArithmeticExpression>
+}
+
+/// A sequence of turtle commands a module expands to during interpretation, such as `L(s)` expanding
+/// to `[ +(30) F(s) ]`. The parameter values of the module are bound to the given parameter names, in order,
+/// and can be used in the parameter expressions of the commands, together with the drawing variables such as `step`.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct CommandMacro {
+    pub parameters: Vec<char>,
+    pub commands: Vec<CommandTemplate>
+}
+
+/// A command macro with all parameter expressions compiled. Module parameters are assigned to slots in order.
+/// Only the definition is serialized; the expressions are compiled again on deserialization.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(try_from = "CommandMacro", into = "CommandMacro"))]
+struct CompiledMacro {
+    definition: CommandMacro,
+    commands: Vec<(TurtleCommand, Vec<CompiledExpression>)>
+}
+
+impl TryFrom<CommandMacro> for CompiledMacro {
+    type Error = MappingError;
+
+    fn try_from(definition: CommandMacro) -> Result<CompiledMacro, MappingError> {
+        CompiledMacro::new(definition)
+    }
+}
+
+impl From<CompiledMacro> for CommandMacro {
+    fn from(compiled: CompiledMacro) -> CommandMacro {
+        compiled.definition
+    }
+}
+
+impl CompiledMacro {
+    fn new(definition: CommandMacro) -> Result<CompiledMacro, MappingError> {
+        for (i, p) in definition.parameters.iter().enumerate() {
+            if definition.parameters[..i].contains(p) {
+                return Err(MappingError::Evaluation(EvaluationError::DuplicateParameter(*p)));
+            }
+        }
+
+        let mut commands = Vec::with_capacity(definition.commands.len());
+
+        for command in &definition.commands {
+            if(command.parameters.len() > command.operation.max_parameters()) {
+                return Err(MappingError::TooManyParameters{
+                    operation: command.operation,
+                    count: command.parameters.len(),
+                    accepted: command.operation.max_parameters()
+                });
+            }
+
+            commands.push((
+                command.operation,
+                command.parameters.iter()
+                    .map(|expr| CompiledExpression::arithmetic_with_variables(expr, &definition.parameters))
+                    .collect::<Result<_, _>>()?
+            ));
+        }
+
+        Ok(CompiledMacro {
+            definition,
+            commands
+        })
+    }
+
+    fn expand(&self, parameters: &[f64], module_index: usize, context: &mut InterpretationContext, commands: &mut Vec<DrawingCommand>) {
+        let InterpretationContext{ variables, stack } = context;
+
+        for (operation, expressions) in &self.commands {
+            commands.push(DrawingCommand::BasicCommand{
+                operation: *operation,
+                parameters: expressions.iter().map(|expr| expr.eval_with_variables(parameters, variables, stack)).collect(),
+                module_index
+            });
+        }
+    }
+}
+
+/// State needed while interpreting a module string: the values of the drawing variables used by command macros, and
+/// scratch space for evaluating their parameter expressions, which is reused for all modules.
+#[derive(Debug, Clone)]
+pub struct InterpretationContext {
+    /// Values of the drawing variables, in the order of `DrawingVariable::ALL`
+    variables: Vec<f64>,
+    stack: Vec<f64>
+}
+
+impl InterpretationContext {
+    /// Create a context providing the drawing variables of given drawing parameters.
+    pub fn new(parameters: &DrawingParameters) -> InterpretationContext {
+        InterpretationContext {
+            variables: DrawingVariable::ALL.iter().map(|v| v.value(parameters)).collect(),
+            stack: Vec::new()
+        }
+    }
+}
+
+/// The conventional mapping of symbols to turtle commands, as used in "The Algorithmic Beauty of Plants"
+/// by Prusinkiewicz and Lindenmayer.
+const STANDARD_MAPPING: [(char, TurtleCommand); 18] = [
+    ('F', TurtleCommand::Forward),
+    ('f', TurtleCommand::ForwardNoDraw),
+    ('G', TurtleCommand::ForwardNoRecord),
+    ('+', TurtleCommand::TurnLeft),
+    ('-', TurtleCommand::TurnRight),
+    ('&', TurtleCommand::PitchDown),
+    ('^', TurtleCommand::PitchUp),
+    ('\\', TurtleCommand::RollLeft),
+    ('/', TurtleCommand::RollRight),
+    ('|', TurtleCommand::TurnAround),
+    ('$', TurtleCommand::RollToHorizontal),
+    ('[', TurtleCommand::SaveState),
+    (']', TurtleCommand::LoadState),
+    ('{', TurtleCommand::BeginPolygon),
+    ('}', TurtleCommand::EndPolygon),
+    ('.', TurtleCommand::SubmitVertex),
+    ('!', TurtleCommand::DecrementLineWidth),
+    ('\'', TurtleCommand::IncrementColor)
+];
+
+/// A mapping for planar systems, in which both `F` and `G` draw lines, and both `f` and `g` move without drawing.
+const PLANAR_MAPPING: [(char, TurtleCommand); 9] = [
+    ('F', TurtleCommand::Forward),
+    ('G', TurtleCommand::Forward),
+    ('f', TurtleCommand::ForwardNoDraw),
+    ('g', TurtleCommand::ForwardNoDraw),
+    ('+', TurtleCommand::TurnLeft),
+    ('-', TurtleCommand::TurnRight),
+    ('|', TurtleCommand::TurnAround),
+    ('[', TurtleCommand::SaveState),
+    (']', TurtleCommand::LoadState)
+];
+
+/// How the interpretation engine treats modules that have no interpretation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum UnmappedPolicy {
+    /// Skip such modules.
+    Ignore,
+    /// Skip such modules, and add a `Diagnostic::MissingInterpretation` warning for each such identifier to the report.
+    Warn,
+    /// Fail interpretation with `LSystemError::UnmappedSymbol`.
+    Error
+}
+
+/// Information about an interpretation run, returned alongside the drawing commands.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct InterpretationReport {
+    /// Every identifier encountered in modules that were not interpreted, with the number of such modules.
+    pub uninterpreted: BTreeMap<char, usize>,
+    /// Warnings raised according to the unmapped symbol policy, in order of first occurrence.
+    pub warnings: Vec<Diagnostic>
+}
+
+impl InterpretationReport {
+    pub fn new() -> InterpretationReport {
+        InterpretationReport::default()
+    }
+
+    /// Check whether all modules were interpreted.
+    pub fn is_complete(&self) -> bool {
+        self.uninterpreted.is_empty()
+    }
+}
 
 /// A struct implementing the interpretation of a iterated module string as a series
 /// of drawing commands. A drawing command is either a direct turtle command, or a special
 /// command based on module annotations, such as the creation of a patch. This struct allows
-/// the association of module identifiers with basic turtle commands.
+/// the association of module identifiers with basic turtle commands, with command macros
+/// expanding to sequences of turtle commands, and with application defined custom commands.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct InterpretationEngine {
     /// Mapping between identifiers and turtle commands.
-    internal_map: HashMap<char, TurtleCommand>
+    internal_map: HashMap<char, TurtleCommand>,
+    /// Mapping between identifiers and command macros.
+    macros: HashMap<char, CompiledMacro>,
+    /// Mapping between identifiers and ids of custom commands.
+    custom_map: HashMap<char, u32>,
+    /// Mapping between ids of application defined annotations and turtle commands.
+    annotation_map: HashMap<u32, TurtleCommand>,
+    /// Mapping between ids of application defined annotations and ids of custom commands.
+    annotation_custom_map: HashMap<u32, u32>,
+    /// How modules without interpretation are treated
+    unmapped_policy: UnmappedPolicy,
+    /// Annotation prefixes known when parsing module strings, rules and mappings
+    annotations: AnnotationRegistry
+}
+
+impl Default for InterpretationEngine {
+    fn default() -> InterpretationEngine {
+        InterpretationEngine::new()
+    }
 }
 
 impl InterpretationEngine {
-    /// Associate given identifier with given turtle command
+    /// Associate given identifier with given turtle command. This replaces any macro or custom command associated
+    /// with the identifier.
     pub fn associate(&mut self, character:char, operation: TurtleCommand) {
-		self.internal_map.remove(&character);
-		self.internal_map.insert(character, operation);
-	}
+        self.macros.remove(&character);
+        self.custom_map.remove(&character);
+        self.internal_map.insert(character, operation);
+    }
 
-    /// Retrieve the associated interpretation for given identifier. This will panic
-    /// if no such association exists.
-	fn retrieve(&self, character: char) -> TurtleCommand {
-		match self.internal_map.get(&character) {
-			Some(operation) => return operation.clone(),
-			None => panic!("Interpretation map does not contain definition for character {}", character)
-		}
+    /// Associate given identifier with the custom command registered under given id, see `custom::CustomCommand`.
+    /// This replaces any turtle command or macro associated with the identifier.
+    pub fn associate_custom(&mut self, character: char, id: u32) {
+        self.macros.remove(&character);
+        self.internal_map.remove(&character);
+        self.custom_map.insert(character, id);
+    }
+
+    /// Associate given identifier with a command macro. This replaces any turtle command or custom command associated
+    /// with the identifier. Macros can only expand to turtle commands. Fails if a parameter expression references a parameter not bound by the macro, or if a command
+    /// is passed more parameters than it accepts.
+    pub fn define_macro(&mut self, character: char, definition: CommandMacro) -> Result<(), MappingError> {
+        let compiled = CompiledMacro::new(definition)?;
+
+        self.internal_map.remove(&character);
+        self.custom_map.remove(&character);
+        self.macros.insert(character, compiled);
+        Ok(())
+    }
+
+    /// Define a command macro from its textual representation, e.g. `parse_macro("L(s)", "[ +(30) F(s) ]")`.
+    /// The symbols in the expansion are resolved to the turtle commands they are currently associated with.
+    pub fn parse_macro(&mut self, signature: &str, expansion: &str) -> Result<(), MappingError> {
+        let signature = grammar::lsystem_parser::signature(signature, &self.annotations)
+            .map_err(|e| MappingError::Syntax(e.to_string()))?;
+
+        let templates = grammar::lsystem_parser::mapping_template_string(expansion, &self.annotations)
+            .map_err(|e| MappingError::Syntax(e.to_string()))?;
+
+        self.define_parsed_macro(signature, templates)
+    }
+
+    /// Define a command macro from an interpretation mapping such as `A(x,y) => F(x*step) +(y*10)`. Parameter
+    /// expressions can reference the module parameters as well as the drawing variables (see `DrawingVariable`), which
+    /// allows drawing the same module string at different scales. The symbols on the right side are resolved to
+    /// the turtle commands they are currently associated with.
+    pub fn parse_mapping(&mut self, mapping: &str) -> Result<(), MappingError> {
+        let (signature, templates) = grammar::lsystem_parser::mapping(mapping, &self.annotations)
+            .map_err(|e| MappingError::Syntax(e.to_string()))?;
+
+        self.define_parsed_macro(signature, templates)
+    }
+
+    /// Define command macros from a list of interpretation mappings, one per line. Mappings are defined in order,
+    /// which means they may not refer to each other. On error, the mappings preceding the failing one stay defined.
+    pub fn parse_mappings(&mut self, mappings: &str) -> Result<(), MappingError> {
+        let mappings = grammar::lsystem_parser::mapping_list(mappings, &self.annotations)
+            .map_err(|e| MappingError::Syntax(e.to_string()))?;
+
+        for (signature, templates) in mappings {
+            self.define_parsed_macro(signature, templates)?;
+        }
+
+        Ok(())
+    }
+
+    fn define_parsed_macro(&mut self, signature: ModuleSignature, templates: Vec<ModuleTemplate>) -> Result<(), MappingError> {
+        if(signature.annotation.is_some()) {
+            return Err(MappingError::Annotation(signature.identifier));
+        }
+
+        let commands = templates.into_iter()
+            .map(|template| match self.retrieve(template.identifier) {
+                _ if template.annotation.is_some() => Err(MappingError::Annotation(template.identifier)),
+                Some(operation) => Ok(CommandTemplate{ operation, parameters: template.parameter_expressions }),
+                None => Err(MappingError::UnknownSymbol(template.identifier))
+            })
+            .collect::<Result<_, _>>()?;
+
+        self.define_macro(signature.identifier, CommandMacro{ parameters: signature.parameters, commands })
+    }
+
+    /// Associate modules carrying the application defined annotation with given id with given turtle command,
+    /// regardless of their identifier. See `register_annotation`.
+    pub fn associate_annotation(&mut self, id: u32, operation: TurtleCommand) {
+        self.annotation_custom_map.remove(&id);
+        self.annotation_map.insert(id, operation);
+    }
+
+    /// Associate modules carrying the application defined annotation with given id with the custom command
+    /// registered under given command id, regardless of their identifier.
+    pub fn associate_annotation_custom(&mut self, id: u32, command_id: u32) {
+        self.annotation_map.remove(&id);
+        self.annotation_custom_map.insert(id, command_id);
+    }
+
+    /// Register a prefix for the application defined annotation with given id, which is then recognized when parsing
+    /// module strings, rules and mappings with this engine. See `AnnotationRegistry::register`.
+    pub fn register_annotation(&mut self, id: u32, prefix: &str) -> Result<(), AnnotationError> {
+        self.annotations.register(id, prefix)
+    }
+
+    /// Remove the prefix of the application defined annotation with given id. Returns whether it was registered.
+    pub fn unregister_annotation(&mut self, id: u32) -> bool {
+        self.annotations.unregister(id)
+    }
+
+    /// Retrieve the annotation prefixes known to this engine.
+    pub fn annotations(&self) -> &AnnotationRegistry {
+        &self.annotations
+    }
+
+    /// Set how modules without interpretation are treated. By default, they are ignored.
+    pub fn set_unmapped_policy(&mut self, policy: UnmappedPolicy) {
+        self.unmapped_policy = policy;
+    }
+
+    pub fn unmapped_policy(&self) -> UnmappedPolicy {
+        self.unmapped_policy
+    }
+
+    /// Retrieve the command macro associated with given identifier, if any.
+    pub fn command_macro(&self, character: char) -> Option<&CommandMacro> {
+        self.macros.get(&character).map(|m| &m.definition)
+    }
+
+    /// Retrieve the associated interpretation for given identifier, if any.
+	fn retrieve(&self, character: char) -> Option<TurtleCommand> {
+		self.internal_map.get(&character).cloned()
 	}
 
     /// Check whether an interpretation for given identifier exists within this engine.
-	fn has_interpretation(&self, character: char) -> bool {
-		return self.internal_map.contains_key(&character);
+	pub fn has_interpretation(&self, character: char) -> bool {
+		return self.internal_map.contains_key(&character) || self.macros.contains_key(&character) || self.custom_map.contains_key(&character);
+	}
+
+    /// Retrieve the maximum number of parameters the interpretation of modules with given identifier and
+    /// annotation accepts, or `None` if such modules have no interpretation.
+	pub fn max_parameters(&self, character: char, annotation: Option<ModuleAnnotation>) -> Option<usize> {
+		match annotation {
+			Some(ModuleAnnotation::CreatePatch) |
+			Some(ModuleAnnotation::Sphere) |
+			Some(ModuleAnnotation::Circle) |
+			Some(ModuleAnnotation::Instance) => Some(1),
+			Some(ModuleAnnotation::Label) => Some(usize::MAX),
+			// Custom commands receive all parameters of the module
+			Some(ModuleAnnotation::Custom(id)) if self.annotation_custom_map.contains_key(&id) => Some(usize::MAX),
+			Some(ModuleAnnotation::Custom(id)) => self.annotation_map.get(&id).map(|operation| operation.max_parameters()),
+			None if self.custom_map.contains_key(&character) => Some(usize::MAX),
+			None => match self.macros.get(&character) {
+				Some(m) => Some(m.definition.parameters.len()),
+				None => self.retrieve(character).map(|operation| operation.max_parameters())
+			}
+		}
 	}
 
     /// Clear all stored associations.
 	pub fn clear(&mut self) {
 		self.internal_map.clear();	
+		self.macros.clear();
+		self.custom_map.clear();
+		self.annotation_map.clear();
+		self.annotation_custom_map.clear();
 	}
 
     /// Create a new, empty interpretation engine.
 	pub fn new() -> InterpretationEngine {
 		return InterpretationEngine {
-            internal_map: HashMap::new()
+            internal_map: HashMap::new(),
+            macros: HashMap::new(),
+            custom_map: HashMap::new(),
+            annotation_map: HashMap::new(),
+            annotation_custom_map: HashMap::new(),
+            unmapped_policy: UnmappedPolicy::Ignore,
+            annotations: AnnotationRegistry::new()
         };
     }
 
+    /// Create an interpretation engine with the conventional mapping from "The Algorithmic Beauty of Plants":
+    ///
+    /// | Symbol | Command | Symbol | Command |
+    /// | --- | --- | --- | --- |
+    /// | `F` | Forward | `f` | ForwardNoDraw |
+    /// | `+` | TurnLeft | `-` | TurnRight |
+    /// | `&` | PitchDown | `^` | PitchUp |
+    /// | `\` | RollLeft | `/` | RollRight |
+    /// | `\|` | TurnAround | `.` | SubmitVertex |
+    /// | `[` | SaveState | `]` | LoadState |
+    /// | `{` | BeginPolygon | `}` | EndPolygon |
+    /// | `!` | DecrementLineWidth | `'` | IncrementColor |
+    /// | `$` | RollToHorizontal | `G` | ForwardNoRecord |
+    pub fn standard() -> InterpretationEngine {
+        Self::with_mapping(&STANDARD_MAPPING)
+    }
+
+    /// Create an interpretation engine with a mapping for planar systems: `F` and `G` draw lines, `f` and `g`
+    /// move without drawing, `+` and `-` turn left and right, `|` turns around, and `[` and `]` save and restore
+    /// the turtle state.
+    pub fn standard_2d() -> InterpretationEngine {
+        Self::with_mapping(&PLANAR_MAPPING)
+    }
+
+    fn with_mapping(mapping: &[(char, TurtleCommand)]) -> InterpretationEngine {
+        let mut engine = InterpretationEngine::new();
+
+        for (character, operation) in mapping {
+            engine.associate(*character, *operation);
+        }
+
+        engine
+    }
+
     /// Interpret given module string based on the associations stored within this
-    /// engine and derive a sequence of drawing commands.
-    pub fn interpret(&self, module_string: &[Module]) -> Vec<DrawingCommand> {
+    /// engine and derive a sequence of drawing commands. Fails if a module has more parameters
+    /// than its interpretation is able to consume, see `TurtleCommand::max_parameters`, or if a module has no
+    /// interpretation and the unmapped symbol policy is `UnmappedPolicy::Error`. Command macros evaluate the drawing
+    /// variables, such as `step`, using given drawing parameters.
+    pub fn interpret(&self, module_string: &ModuleString, parameters: &DrawingParameters) -> Result<Vec<DrawingCommand>, LSystemError> {
+        self.interpret_with_report(module_string, parameters).map(|(commands, _)| commands)
+    }
+
+    /// Interpret given module string like `interpret`, additionally returning a report about the modules
+    /// that were not interpreted.
+    pub fn interpret_with_report(&self, module_string: &ModuleString, parameters: &DrawingParameters) -> Result<(Vec<DrawingCommand>, InterpretationReport), LSystemError> {
         let mut commands = Vec::new();
+        let mut report = InterpretationReport::new();
+        let mut context = InterpretationContext::new(parameters);
+
+        for (i, module) in module_string.iter().enumerate() {
+            self.interpret_module(module, i, &mut context, &mut commands, &mut report)?;
+        }
+
+        Ok((commands, report))
+    }
+
+    /// Interpret a single module, located at given index in the module string, and append the resulting drawing
+    /// commands to given list. Modules without interpretation do not produce any commands, and are recorded in
+    /// given report according to the unmapped symbol policy. The same context should be used for all modules of a
+    /// module string.
+    pub fn interpret_module(&self, module: ModuleView, index: usize, context: &mut InterpretationContext, commands: &mut Vec<DrawingCommand>, report: &mut InterpretationReport) -> Result<(), LSystemError> {
+        // Annotations with a single optional parameter
+        let parameter = || match module.parameter_count() {
+            0 => Ok(None),
+            1 => Ok(Some(module.parameter_values[0])),
+            n => Err(LSystemError::TooManyParameters{ identifier: module.identifier, count: n, accepted: 1, module_index: index })
+        };
+
+        match module.annotation {
+            Some(ModuleAnnotation::CreatePatch) => {
+                commands.push(
+                    DrawingCommand::SpawnPatch{
+                        patch_id: module.identifier,
+                        scaling: parameter()?.unwrap_or(1.0),
+                        module_index: index
+                    }
+                );
+
+                Ok(())
+            },
+            Some(ModuleAnnotation::Sphere) => {
+                commands.push(DrawingCommand::SpawnSphere{ radius: parameter()?, module_index: index });
+                Ok(())
+            },
+            Some(ModuleAnnotation::Circle) => {
+                commands.push(DrawingCommand::SpawnCircle{ radius: parameter()?, module_index: index });
+                Ok(())
+            },
+            Some(ModuleAnnotation::Instance) => {
+                commands.push(
+                    DrawingCommand::SpawnInstance{
+                        identifier: module.identifier,
+                        scaling: parameter()?.unwrap_or(1.0),
+                        module_index: index
+                    }
+                );
+
+                Ok(())
+            },
+            Some(ModuleAnnotation::Label) => {
+                commands.push(
+                    DrawingCommand::SpawnLabel{
+                        identifier: module.identifier,
+                        values: module.parameter_values.to_vec(),
+                        module_index: index
+                    }
+                );
 
-        for module in module_string {
-            match module.annotation {
-                Some(ModuleAnnotation::CreatePatch) => {
-                    let scaling = match module.parameter_count() {
-                        0 => 1.0,
-                        1 => module.parameter_values[0],
-                        _ => panic!("Found module annotated as CreatePatch, but has more than one parameter value: {}", module)
-                    };
-                                 
-                    commands.push(
-                        DrawingCommand::SpawnPatch{
-                            patch_id: module.identifier,
-                            scaling: scaling
-                        }
-                    );
-                },
-                None =>  {
-                    if self.has_interpretation(module.identifier) {
-                        let operation = self.retrieve(module.identifier);
-
-                        let param = match module.parameter_count() {
-                            0 => None,
-                            1 => Some(module.parameter_values[0]),
-                            _ => panic!("Turtle commands can't have more than one parameters, but has {}: {}", module.parameter_count(), module)	
-                        };
-
-                        commands.push(
-                            DrawingCommand::BasicCommand{
-                                operation: operation,
-                                parameter: param 
-                            }
-                        );
+                Ok(())
+            },
+            Some(ModuleAnnotation::Custom(id)) => {
+                if let Some(command_id) = self.annotation_custom_map.get(&id) {
+                    commands.push(Self::custom_command(*command_id, module, index));
+                    return Ok(());
+                }
+
+                match self.annotation_map.get(&id) {
+                    Some(operation) => self.interpret_operation(*operation, module, index, commands),
+                    None => self.report_unmapped(module, index, report)
+                }
+            },
+            None =>  {
+                if let Some(id) = self.custom_map.get(&module.identifier) {
+                    commands.push(Self::custom_command(*id, module, index));
+                    return Ok(());
+                }
+
+                if let Some(m) = self.macros.get(&module.identifier) {
+                    let expected = m.definition.parameters.len();
+
+                    if(module.parameter_count() > expected) {
+                        return Err(LSystemError::TooManyParameters{ identifier: module.identifier, count: module.parameter_count(), accepted: expected, module_index: index });
+                    } else if(module.parameter_count() < expected) {
+                        return Err(LSystemError::MissingParameters{ identifier: module.identifier, count: module.parameter_count(), expected, module_index: index });
                     }
+
+                    m.expand(module.parameter_values, index, context, commands);
+                    return Ok(());
+                }
+
+                match self.retrieve(module.identifier) {
+                    Some(operation) => self.interpret_operation(operation, module, index, commands),
+                    None => self.report_unmapped(module, index, report)
                 }
             }
         }
+    }
+
+    /// Handle given module without interpretation according to the unmapped symbol policy.
+    fn report_unmapped(&self, module: ModuleView, index: usize, report: &mut InterpretationReport) -> Result<(), LSystemError> {
+        if(self.unmapped_policy == UnmappedPolicy::Error) {
+            return Err(LSystemError::UnmappedSymbol{ identifier: module.identifier, module_index: index });
+        }
+
+        let count = report.uninterpreted.entry(module.identifier).or_insert(0);
+
+        if(*count == 0 && self.unmapped_policy == UnmappedPolicy::Warn) {
+            report.warnings.push(Diagnostic::MissingInterpretation{ identifier: module.identifier });
+        }
 
-        commands
+        *count += 1;
+        Ok(())
+    }
+
+    /// Create a drawing command executing the custom command with given id, passing on all parameters of given module.
+    fn custom_command(id: u32, module: ModuleView, index: usize) -> DrawingCommand {
+        DrawingCommand::CustomCommand{
+            id,
+            parameters: module.parameter_values.to_vec(),
+            module_index: index
+        }
+    }
+
+    /// Interpret given module as given turtle command, passing on its parameters.
+    fn interpret_operation(&self, operation: TurtleCommand, module: ModuleView, index: usize, commands: &mut Vec<DrawingCommand>) -> Result<(), LSystemError> {
+        if(module.parameter_count() > operation.max_parameters()) {
+            return Err(LSystemError::TooManyParameters{
+                identifier: module.identifier,
+                count: module.parameter_count(),
+                accepted: operation.max_parameters(),
+                module_index: index
+            });
+        }
+
+        commands.push(
+            DrawingCommand::BasicCommand{
+                operation,
+                parameters: module.parameter_values.to_vec(),
+                module_index: index
+            }
+        );
+
+        Ok(())
     }
 }
 
 
-/// A drawing command derived from a module of an iterated module string.
+/// A drawing command derived from a module of an iterated module string. Every command carries the index
+/// of the module it was derived from, which is passed on to all primitives created by it.
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum DrawingCommand {
-    /// A basic turtle command, with an optional argument
-    BasicCommand { operation: TurtleCommand, parameter: Option<f64> },
+    /// A basic turtle command, with its arguments. See `TurtleCommand::max_parameters` for their meaning.
+    BasicCommand { operation: TurtleCommand, parameters: Vec<f64>, module_index: usize },
+    /// Execute the application defined command registered under given id, with all parameters of the module.
+    CustomCommand { id: u32, parameters: Vec<f64>, module_index: usize },
     /// Spawn a patch at this position.
-    SpawnPatch { patch_id: char, scaling: f64 }
+    SpawnPatch { patch_id: char, scaling: f64, module_index: usize },
+    /// Draw a sphere at this position. Without a radius, half of the current line width is used.
+    SpawnSphere { radius: Option<f64>, module_index: usize },
+    /// Draw a circle perpendicular to the heading. Without a radius, half of the current line width is used.
+    SpawnCircle { radius: Option<f64>, module_index: usize },
+    /// Place an instance of an application defined object at this position.
+    SpawnInstance { identifier: char, scaling: f64, module_index: usize },
+    /// Place a label at this position.
+    SpawnLabel { identifier: char, values: Vec<f64>, module_index: usize }
+}
+
+impl DrawingCommand {
+    /// Retrieve the index of the module this command was derived from.
+    pub fn module_index(&self) -> usize {
+        match *self {
+            DrawingCommand::BasicCommand{module_index, ..} |
+            DrawingCommand::CustomCommand{module_index, ..} |
+            DrawingCommand::SpawnPatch{module_index, ..} |
+            DrawingCommand::SpawnSphere{module_index, ..} |
+            DrawingCommand::SpawnCircle{module_index, ..} |
+            DrawingCommand::SpawnInstance{module_index, ..} |
+            DrawingCommand::SpawnLabel{module_index, ..} => module_index
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Operations and parameters of given commands, which all have to be basic commands.
+    fn basic_commands(commands: &[DrawingCommand]) -> Vec<(TurtleCommand, Vec<f64>)> {
+        commands.iter()
+            .map(|c| match c {
+                DrawingCommand::BasicCommand{operation, parameters, ..} => (*operation, parameters.clone()),
+                other => panic!("unexpected command {:?}", other)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn expands_parsed_macros() {
+        let mut mapped = InterpretationEngine::standard();
+        mapped.parse_mapping("L(s) => [ +(30) F(s*step) ]").unwrap();
+
+        let mut defined = InterpretationEngine::standard();
+        defined.parse_macro("L(s)", "[ +(30) F(s*step) ]").unwrap();
+
+        let mut parameters = DrawingParameters::new();
+        parameters.step = 2.0;
+
+        for engine in &[mapped, defined] {
+            let definition = engine.command_macro('L').unwrap();
+            assert_eq!(definition.parameters, vec!['s']);
+            assert_eq!(definition.commands.len(), 4);
+
+            let modules = ModuleString::from(grammar::lsystem_parser::module_string("F(1)L(3)", engine.annotations()).unwrap());
+            let commands = engine.interpret(&modules, &parameters).unwrap();
+
+            assert_eq!(basic_commands(&commands), vec![
+                (TurtleCommand::Forward, vec![1.0]),
+                (TurtleCommand::SaveState, vec![]),
+                (TurtleCommand::TurnLeft, vec![30.0]),
+                (TurtleCommand::Forward, vec![6.0]),
+                (TurtleCommand::LoadState, vec![])
+            ]);
+            assert_eq!(commands.iter().map(|c| c.module_index()).collect::<Vec<_>>(), vec![0, 1, 1, 1, 1]);
+        }
+    }
+
+    #[test]
+    fn rejects_annotated_modules_in_mappings() {
+        let mut engine = InterpretationEngine::standard();
+
+        assert_eq!(engine.parse_mapping("L(s) => [ @OF(s) ]"), Err(MappingError::Annotation('F')));
+        assert_eq!(engine.parse_mapping("~L(s) => F(s)"), Err(MappingError::Annotation('L')));
+        assert_eq!(engine.parse_macro("L(s)", "~F(s)"), Err(MappingError::Annotation('F')));
+        assert!(!engine.has_interpretation('L'));
+    }
 }
@@ -1,21 +1,58 @@
 #![allow(dead_code)]
 
-use std::string::*;
 use std::collections::*;
 use std::fmt::*;
 use rand::*;
-use rand::rngs::*;
 use rand::distributions::*;
+use rand_chacha::ChaCha20Rng;
+#[cfg(feature = "serde")]
+use serde_derive::*;
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
 use crate::util::*;
+use crate::validation::*;
+use crate::error::*;
+use crate::compiler::*;
+use crate::limits::*;
+use crate::module_string::*;
+use crate::annotation::*;
+use crate::checkpoint::*;
+use crate::drawing::DrawingVariable;
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
 
 trait Evaluatable {
 	type Result;
-	fn eval(&self, env: &Environment) -> Self::Result;
+	fn eval(&self, env: &Environment) -> std::result::Result<Self::Result, EvaluationError>;
+}
+
+/// An error caused by evaluating expressions or binding parameters in an environment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvaluationError {
+	/// The given parameter has no definition in the environment.
+	UnboundParameter(char),
+	/// The given parameter was defined more than once.
+	DuplicateParameter(char),
+	/// The given drawing variable is referenced in an expression that is not part of an interpretation mapping.
+	UnboundVariable(DrawingVariable)
+}
+
+impl EvaluationError {
+	/// Attach information about where this error occured during iteration.
+	pub fn at(self, module_index: usize, rule: usize) -> LSystemError {
+		match self {
+			EvaluationError::UnboundParameter(p) => LSystemError::UnboundParameter{ parameter: p, module_index, rule },
+			EvaluationError::DuplicateParameter(p) => LSystemError::DuplicateParameter{ parameter: p, module_index, rule },
+			EvaluationError::UnboundVariable(v) => LSystemError::UnboundVariable{ variable: v, module_index, rule }
+		}
+	}
 }
 
 /// An expression that evaluates to a number. It is used to both create new parameter values
 /// when iterating a module string, as well as in boolean expressions in module patterns.
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum ArithmeticExpression {
 	Add(Box<ArithmeticExpression>, Box<ArithmeticExpression>),
 	Sub(Box<ArithmeticExpression>, Box<ArithmeticExpression>),
@@ -24,27 +61,45 @@ pub enum ArithmeticExpression {
 	Pow(Box<ArithmeticExpression>, Box<ArithmeticExpression>),
 	Neg(Box<ArithmeticExpression>),
 	Const(f64),
-	Param(char)
+	Param(char),
+	/// A drawing parameter, only available in interpretation mappings.
+	Variable(DrawingVariable)
 }
 
 impl Evaluatable for ArithmeticExpression {
 	type Result = f64;
 
-	fn eval(&self, env: &Environment) -> Self::Result {
-		match *self {
-			ArithmeticExpression::Add(ref left, ref right) => left.eval(env) + right.eval(env),
-			ArithmeticExpression::Sub(ref left, ref right) => left.eval(env) - right.eval(env),
-			ArithmeticExpression::Mul(ref left, ref right) => left.eval(env) * right.eval(env),
-			ArithmeticExpression::Div(ref left, ref right) => left.eval(env) / right.eval(env),
-			ArithmeticExpression::Pow(ref left, ref right) => left.eval(env).powf(right.eval(env)),
-			ArithmeticExpression::Neg(ref expr) => -expr.eval(env),
+	fn eval(&self, env: &Environment) -> std::result::Result<Self::Result, EvaluationError> {
+		Ok(match *self {
+			ArithmeticExpression::Add(ref left, ref right) => left.eval(env)? + right.eval(env)?,
+			ArithmeticExpression::Sub(ref left, ref right) => left.eval(env)? - right.eval(env)?,
+			ArithmeticExpression::Mul(ref left, ref right) => left.eval(env)? * right.eval(env)?,
+			ArithmeticExpression::Div(ref left, ref right) => left.eval(env)? / right.eval(env)?,
+			ArithmeticExpression::Pow(ref left, ref right) => left.eval(env)?.powf(right.eval(env)?),
+			ArithmeticExpression::Neg(ref expr) => -expr.eval(env)?,
 			ArithmeticExpression::Const(x) => x,
-			ArithmeticExpression::Param(p) => {
-				if(!env.has_parameter(p)) {
-					panic!("No definition for parameter '{}' found in environment", p);				
-				}
-				env.get_parameter_value(p)
-			}
+			ArithmeticExpression::Param(p) => env.get_parameter_value(p)?,
+			ArithmeticExpression::Variable(v) => return Err(EvaluationError::UnboundVariable(v))
+		})
+	}
+}
+
+impl ArithmeticExpression {
+	/// Call given function for every parameter name referenced in this expression,
+	/// in order of appearance.
+	pub fn visit_parameters<F: FnMut(char)>(&self, f: &mut F) {
+		match *self {
+			ArithmeticExpression::Add(ref left, ref right) |
+			ArithmeticExpression::Sub(ref left, ref right) |
+			ArithmeticExpression::Mul(ref left, ref right) |
+			ArithmeticExpression::Div(ref left, ref right) |
+			ArithmeticExpression::Pow(ref left, ref right) => {
+				left.visit_parameters(f);
+				right.visit_parameters(f);
+			},
+			ArithmeticExpression::Neg(ref expr) => expr.visit_parameters(f),
+			ArithmeticExpression::Const(_) | ArithmeticExpression::Variable(_) => (),
+			ArithmeticExpression::Param(p) => f(p)
 		}
 	}
 }
@@ -59,13 +114,15 @@ impl Display for ArithmeticExpression {
 			ArithmeticExpression::Pow(ref left, ref right) => write!(f, "({}^{})", left, right),
 			ArithmeticExpression::Neg(ref expr) => write!(f, "(-{})", expr),
 			ArithmeticExpression::Const(x) => write!(f, "{}", x),
-			ArithmeticExpression::Param(p) => write!(f, "{}", p)
+			ArithmeticExpression::Param(p) => write!(f, "{}", p),
+			ArithmeticExpression::Variable(v) => write!(f, "{}", v.name())
 		}
     }
 }
 
 /// A boolean expression used in module pattern as part of the left side of rules.
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum BooleanExpression {
 	Not(Box<BooleanExpression>),
 	And(Box<BooleanExpression>, Box<BooleanExpression>),
@@ -94,27 +151,52 @@ impl Display for BooleanExpression {
     }
 }
 
+impl BooleanExpression {
+	/// Call given function for every parameter name referenced in this expression,
+	/// in order of appearance.
+	pub fn visit_parameters<F: FnMut(char)>(&self, f: &mut F) {
+		match *self {
+			BooleanExpression::Not(ref expr) => expr.visit_parameters(f),
+			BooleanExpression::And(ref left, ref right) |
+			BooleanExpression::Or(ref left, ref right) => {
+				left.visit_parameters(f);
+				right.visit_parameters(f);
+			},
+			BooleanExpression::Lth(ref left, ref right) |
+			BooleanExpression::Leq(ref left, ref right) |
+			BooleanExpression::Gth(ref left, ref right) |
+			BooleanExpression::Geq(ref left, ref right) |
+			BooleanExpression::Eq(ref left, ref right) => {
+				left.visit_parameters(f);
+				right.visit_parameters(f);
+			},
+			BooleanExpression::Const(_) => ()
+		}
+	}
+}
+
 impl Evaluatable for BooleanExpression {
 	type Result = bool;
 
-	fn eval(&self, env: &Environment) -> Self::Result {
-		match *self {
-			BooleanExpression::Not(ref expr) => !expr.eval(env),
-			BooleanExpression::And(ref left, ref right) => left.eval(env) && right.eval(env),
-			BooleanExpression::Or(ref left, ref right) => left.eval(env) || right.eval(env),
-			BooleanExpression::Lth(ref left, ref right) => left.eval(env) < right.eval(env),
-			BooleanExpression::Leq(ref left, ref right) => left.eval(env) <= right.eval(env),
-			BooleanExpression::Gth(ref left, ref right) => left.eval(env) > right.eval(env),
-			BooleanExpression::Geq(ref left, ref right) => left.eval(env) >= right.eval(env),
-			BooleanExpression::Eq(ref left, ref right) => left.eval(env) == right.eval(env),
+	fn eval(&self, env: &Environment) -> std::result::Result<Self::Result, EvaluationError> {
+		Ok(match *self {
+			BooleanExpression::Not(ref expr) => !expr.eval(env)?,
+			BooleanExpression::And(ref left, ref right) => left.eval(env)? && right.eval(env)?,
+			BooleanExpression::Or(ref left, ref right) => left.eval(env)? || right.eval(env)?,
+			BooleanExpression::Lth(ref left, ref right) => left.eval(env)? < right.eval(env)?,
+			BooleanExpression::Leq(ref left, ref right) => left.eval(env)? <= right.eval(env)?,
+			BooleanExpression::Gth(ref left, ref right) => left.eval(env)? > right.eval(env)?,
+			BooleanExpression::Geq(ref left, ref right) => left.eval(env)? >= right.eval(env)?,
+			BooleanExpression::Eq(ref left, ref right) => left.eval(env)? == right.eval(env)?,
 			BooleanExpression::Const(val) => val
-		}
+		})
 	}
 }
 
 /// A statement used in the execution block of a rule. Statements can be evaluated, which causes
 /// 
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Statement {
 	IfThenElse(Box<BooleanExpression>, Box<Statement>, Option<Box<Statement>>),
 	Assignment(char, Box<ArithmeticExpression>)
@@ -123,46 +205,69 @@ pub enum Statement {
 
 /// Environment used for binding parameter names to actual values. Used when checking if a module
 /// satisfies a module pattern with condition, as well as when creating a module from a module 
-/// template when applying a rule.
+/// template when applying a rule. Bindings are stored in slots in the order they were defined, and
+/// the storage is retained when the environment is cleared, which allows a single environment to be
+/// reused for all modules of an iteration without allocating.
 #[derive(Debug, Clone)]
 pub struct Environment {
-	parameter_map: HashMap<char, f64>
+	names: Vec<char>,
+	values: Vec<f64>
+}
+
+impl Default for Environment {
+	fn default() -> Environment {
+		Environment::new()
+	}
 }
 
 impl Environment {
-	fn new() -> Environment {
-		return Environment { parameter_map: HashMap::new() };	
+	pub fn new() -> Environment {
+		return Environment { names: Vec::new(), values: Vec::new() };	
+	}
+
+	/// Remove all bindings, keeping the allocated slots.
+	pub fn clear(&mut self) {
+		self.names.clear();
+		self.values.clear();
+	}
+
+	/// Retrieve the slot the given parameter is bound to, if any.
+	fn slot(&self, param: char) -> Option<usize> {
+		self.names.iter().position(|p| *p == param)
 	}
 
 	fn has_parameter(&self, param: char) -> bool {
-		self.parameter_map.contains_key(&param)
+		self.slot(param).is_some()
 	}
 
-	fn get_parameter_value(&self, param: char) -> f64 {
-		match self.parameter_map.get(&param) {
-			Some(value) => *value,
-			None => panic!("Environment does not contain value for parameter {}", param)
+	fn get_parameter_value(&self, param: char) -> std::result::Result<f64, EvaluationError> {
+		match self.slot(param) {
+			Some(slot) => Ok(self.values[slot]),
+			None => Err(EvaluationError::UnboundParameter(param))
 		}	
 	}
 	
-	fn define_parameter(&mut self, param: char, value: f64) {
+	fn define_parameter(&mut self, param: char, value: f64) -> std::result::Result<(), EvaluationError> {
 		if(self.has_parameter(param)) {
-			panic!("Tried to define parameter {} as {} but already exists as {}", param, value, self.get_parameter_value(param));		
+			return Err(EvaluationError::DuplicateParameter(param));
 		}
 
-		self.parameter_map.insert(param, value);
+		self.names.push(param);
+		self.values.push(value);
+		Ok(())
 	}	
 }
 
 impl Display for Environment {
 	fn fmt(&self, f: &mut Formatter<'_>) -> Result {
-		write!(f, "{:?}", self.parameter_map)
+		f.debug_map().entries(self.names.iter().zip(self.values.iter())).finish()
     }
 }
 
 /// A description of how a module "looks" like, e.g. "A(x,y,z)".
 /// This is used as part of module patterns as part of iteration rules.
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct ModuleSignature {
 	pub annotation: Option<ModuleAnnotation>,
 	pub identifier: char,
@@ -170,8 +275,16 @@ pub struct ModuleSignature {
 }
 
 impl ModuleSignature {
+	/// Check whether the given module has the identifier, parameter count and annotation described
+	/// by this signature.
+	fn fits(&self, module: ModuleView) -> bool {
+		self.identifier == module.identifier
+			&& self.parameter_count() == module.parameter_count()
+			&& self.annotation_matches(module)
+	}
+
 	/// Check whether the annotation in this signature fits the one in the given module
-	fn annotation_matches(&self, module: &Module) -> bool {
+	fn annotation_matches(&self, module: ModuleView) -> bool {
 		// If the options have different states they cant match
 		if self.annotation.is_some() != module.annotation.is_some() {
 			return false;
@@ -186,11 +299,11 @@ impl ModuleSignature {
 		return self.annotation.unwrap() == module.annotation.unwrap();
 	}
 
-	fn has_parameters(& self) -> bool {
-		self.parameters.len() > 0	
+	pub fn has_parameters(& self) -> bool {
+		!self.parameters.is_empty()	
 	}
 
-	fn parameter_count(& self) -> usize {
+	pub fn parameter_count(& self) -> usize {
 		return self.parameters.len();	
 	}
 
@@ -230,6 +343,7 @@ impl Display for ModuleSignature {
 /// A template for a module instance used as part of the right side of a iteration rule.
 /// It uses expressions with parameter variables in it, like "A(x+1, y)".
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct ModuleTemplate {
 	pub identifier: char,
 	pub parameter_expressions: Vec<ArithmeticExpression>,
@@ -239,16 +353,16 @@ pub struct ModuleTemplate {
 impl ModuleTemplate {
 	/// Create an actual module instance based on this template. Any expressions
 	/// contained in the template will be evaluated with given environment.
-	pub fn instantiate(& self, env: &Environment) -> Module {
-		Module {
+	pub fn instantiate(& self, env: &Environment) -> std::result::Result<Module, EvaluationError> {
+		Ok(Module {
 			annotation: self.annotation,
 			identifier: self.identifier,
-			parameter_values: self.parameter_expressions.iter().map(|expr| expr.eval(env)).collect()
-		}
+			parameter_values: self.parameter_expressions.iter().map(|expr| expr.eval(env)).collect::<std::result::Result<_, _>>()?
+		})
 	}
 
 	pub fn has_parameters(& self) -> bool {
-		return self.parameter_expressions.len() > 0;	
+		return !self.parameter_expressions.is_empty();	
 	}
 
 	pub fn parameter_count(& self) -> usize {
@@ -290,19 +404,38 @@ impl Display for ModuleTemplate {
 
 /// An annotation that can be part of a module, for example to create a bezier
 /// patch with name A and scaling f, the module would look like "~A(f)"
-/// We use hardcoded annotations, since just interpreting any character in front of a module
+/// Annotations are recognized by a fixed set of prefixes, since just interpreting any character in front of a module
 /// identifier as an annotation would be ambiguous; we want to allow parameterless module strings such as
-/// "+++---A(f)" which contain special characters like '+' and '-'. 
-#[derive(Debug, Clone, Copy, PartialEq)]
+/// "+++---A(f)" which contain special characters like '+' and '-'. Applications can register additional prefixes
+/// using `InterpretationEngine::register_annotation`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum ModuleAnnotation {
-	/// Interpret the module as a command to create a bezier patch at this position.
-	CreatePatch
+	/// Interpret the module as a command to create a bezier patch at this position. Prefix: `~`
+	CreatePatch,
+	/// Draw a sphere at this position, with the radius given as parameter. Prefix: `@O`
+	Sphere,
+	/// Draw a circle perpendicular to the heading, with the radius given as parameter. Prefix: `@c`
+	Circle,
+	/// Place an instance of the object named by the identifier, with the scaling given as parameter. Prefix: `@I`
+	Instance,
+	/// Place a label named by the identifier, carrying the parameter values. Prefix: `@L`
+	Label,
+	/// Application defined annotation, using the prefix registered for given id. Prefix: `@{id}` or the registered one
+	Custom(u32)
 }
 
 impl Display for ModuleAnnotation {
 	fn fmt(&self, f: &mut Formatter<'_>) -> Result {
 		return match self {
-			ModuleAnnotation::CreatePatch => write!(f, "~")
+			ModuleAnnotation::CreatePatch => write!(f, "~"),
+			ModuleAnnotation::Sphere => write!(f, "@O"),
+			ModuleAnnotation::Circle => write!(f, "@c"),
+			ModuleAnnotation::Instance => write!(f, "@I"),
+			ModuleAnnotation::Label => write!(f, "@L"),
+			// Registered prefixes are not known here, but the generic notation can always be parsed back.
+			// See `ModuleString::display_with` for displaying registered prefixes.
+			ModuleAnnotation::Custom(id) => write!(f, "@{{{}}}", id)
 		};
     }
 }
@@ -310,6 +443,7 @@ impl Display for ModuleAnnotation {
 /// A module as its appearing in an actual iteration string. Can have parameter values, like "A(1, 3)", or not,
 /// like "A".
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Module {
 	/// The character used as the identifier of this module.
 	pub identifier: char,
@@ -320,8 +454,13 @@ pub struct Module {
 }
 
 impl Module {
+	/// Display this module using the annotation prefixes of given registry. See `ModuleString::display_with`.
+	pub fn display_with<'a>(&'a self, annotations: &'a AnnotationRegistry) -> AnnotatedDisplay<'a, ModuleView<'a>> {
+		self.view().display_with(annotations)
+	}
+
 	pub fn has_parameters(& self) -> bool {
-		return self.parameter_values.len() > 0;	
+		return !self.parameter_values.is_empty();	
 	}
 
 	pub fn parameter_count(& self) -> usize {
@@ -331,6 +470,15 @@ impl Module {
 	pub fn has_annotation(& self) -> bool {
 		return self.annotation.is_some();
 	}
+
+	/// Retrieve a borrowed view of this module.
+	pub fn view(& self) -> ModuleView<'_> {
+		ModuleView {
+			identifier: self.identifier,
+			parameter_values: &self.parameter_values,
+			annotation: self.annotation
+		}
+	}
 }
 
 impl Display for Module {
@@ -363,45 +511,55 @@ impl Display for Module {
 
 
 /// A module and its immediate surounding modules, which might or might not actually exist.
-#[derive(Debug, Clone)]
-pub struct ModuleContext {
-	pub left: Option<Module>,
-	pub center: Module,
-	pub right: Option<Module>
+/// The modules are borrowed from the module string being iterated.
+#[derive(Debug, Clone, Copy)]
+pub struct ModuleContext<'a> {
+	pub left: Option<ModuleView<'a>>,
+	pub center: ModuleView<'a>,
+	pub right: Option<ModuleView<'a>>
 }
 
-impl ModuleContext {
-	pub fn new(center: Module) -> ModuleContext {
+impl<'a> ModuleContext<'a> {
+	pub fn new(center: ModuleView<'a>) -> ModuleContext<'a> {
 		ModuleContext {
 			left: None,
-			center: center,
+			center,
 			right: None	
 		}
 	}
 
-	pub fn new_with_left(center: Module, left: Module) -> ModuleContext {
+	pub fn new_with_left(center: ModuleView<'a>, left: ModuleView<'a>) -> ModuleContext<'a> {
 		ModuleContext {
 			left: Some(left),
-			center: center,
+			center,
 			right: None	
 		}
 	}
 
-	pub fn new_with_right(center: Module, right: Module) -> ModuleContext {
+	pub fn new_with_right(center: ModuleView<'a>, right: ModuleView<'a>) -> ModuleContext<'a> {
 		ModuleContext {
 			left: None,
-			center: center,
+			center,
 			right: Some(right)
 		}
 	}
 
-	pub fn new_complete(center: Module, left: Module, right: Module) -> ModuleContext {
+	pub fn new_complete(center: ModuleView<'a>, left: ModuleView<'a>, right: ModuleView<'a>) -> ModuleContext<'a> {
 		ModuleContext {
 			left: Some(left),
-			center: center,
+			center,
 			right: Some(right)
 		}
 	}
+
+	/// Create the context of the module at given position in given module string.
+	pub fn at(module_string: &'a ModuleString, index: usize) -> ModuleContext<'a> {
+		ModuleContext {
+			left: if(index > 0) { module_string.get(index - 1) } else { None },
+			center: module_string.at(index),
+			right: module_string.get(index + 1)
+		}
+	}
 }
 
 
@@ -409,6 +567,7 @@ impl ModuleContext {
 /// surounding modules. Parameter conditions can include parameters of both the module and side modules.
 /// A succesfull match will bind variables in the pattern to the actual values.
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct ModulePattern {
 	pub match_left: Option<ModuleSignature>,
 	pub match_center: ModuleSignature,
@@ -417,113 +576,94 @@ pub struct ModulePattern {
 }
 
 impl ModulePattern {
-	/// Check whether the given module context matches this pattern.
-	pub fn does_match(& self, context: &ModuleContext) -> bool {
-		let mut env = Environment::new();
+	/// Check whether the given module context matches this pattern. The given environment is used to
+	/// evaluate the condition, and contains the bound parameters afterwards if the context matched.
+	/// Fails if the condition references parameters that are not bound, or if parameter names are bound
+	/// more than once.
+	pub fn does_match(& self, context: &ModuleContext, env: &mut Environment) -> std::result::Result<bool, EvaluationError> {
+		if !self.fits(context) {
+			return Ok(false);
+		}
 
+		self.bind(context, env)?;
+
+		self.condition.eval(env)
+	}
+
+	/// Check whether the modules in given context have the structure required by this pattern, without
+	/// evaluating the condition.
+	pub fn fits(& self, context: &ModuleContext) -> bool {
+		// Both centers have to have the same identifier and the exact same number of parameters
 		if(self.match_center.identifier != context.center.identifier) {
 			return false;
 		}
 
-		// Both centers have to have the exact same number of parameters
 		if(self.match_center.parameter_count() != context.center.parameter_count()) {
 			return false;		
 		}
 
-		Self::extract_parameters(&self.match_center, &context.center, &mut env);
-
-		if(self.match_left.is_some()) {
-			if(context.left.is_none()) {
-				return false;			
-			}
-		
-			// Check if parameter counts match
-			let match_left = self.match_left.as_ref().unwrap();
-			let left = context.left.as_ref().unwrap();
-
-			if(match_left.identifier != left.identifier) {
-				return false;
-			}
-
-			if(match_left.parameter_count() != left.parameter_count()) {
-				return false;
-			}
-
-			// Check if annotations match
-			if !match_left.annotation_matches(left) {
-				return false;
-			}
-
-			Self::extract_parameters(&match_left, &left, &mut env);
-		}
-
-		if(self.match_right.is_some()) {
-			if(context.right.is_none()) {
-				return false;			
-			}
-		
-			// Check if parameter counts match
-			let match_right = self.match_right.as_ref().unwrap();
-			let right = context.right.as_ref().unwrap();
-
-			if(match_right.identifier != right.identifier) {
-				return false;
-			}
-
-			if(match_right.parameter_count() != right.parameter_count()) {
-				return false;
-			}
+		// Check if the side modules exist and fit their signature
+		Self::side_matches(&self.match_left, context.left) && Self::side_matches(&self.match_right, context.right)
+	}
 
-			// Check if annotations match
-			if !match_right.annotation_matches(right) {
-				return false;
-			}
+	/// Retrieve all parameter names bound by this pattern, in binding order.
+	pub fn bound_parameters(& self) -> Vec<char> {
+		self.match_left.iter()
+			.chain(std::iter::once(&self.match_center))
+			.chain(self.match_right.iter())
+			.flat_map(|s| s.parameters.iter().cloned())
+			.collect()
+	}
 
-			Self::extract_parameters(&match_right, &right, &mut env);
+	fn side_matches(signature: &Option<ModuleSignature>, module: Option<ModuleView>) -> bool {
+		match (signature, module) {
+			(None, _) => true,
+			(Some(_), None) => false,
+			(Some(signature), Some(module)) => signature.fits(module)
 		}
-
-		return self.condition.eval(&env);
 	}
 
-	/// Create an environment in which the parameter variables in this pattern are bound to the values
-	/// present in the given context. This is used to instantiate the module templates in the right side
-	/// of a rule. This function requires that `does_match` returned true.
-	pub fn bind(& self, context: &ModuleContext) -> Environment {
-		let mut env = Environment::new();
+	/// Bind the parameter variables in this pattern to the values present in the given context, replacing
+	/// all previous bindings in given environment. Parameters are always bound in the order left, center, right.
+	/// This is used to instantiate the module templates in the right side of a rule. This function requires
+	/// that `does_match` returned true.
+	pub fn bind(& self, context: &ModuleContext, env: &mut Environment) -> std::result::Result<(), EvaluationError> {
+		env.clear();
 
-		if(self.match_left.is_some()) {
-			Self::extract_parameters(&self.match_left.as_ref().unwrap(), &context.left.as_ref().unwrap(), &mut env);	
+		if let (Some(signature), Some(module)) = (&self.match_left, context.left) {
+			Self::extract_parameters(signature, module.parameter_values, env)?;
 		}
 
-		if(self.match_right.is_some()) {
-			Self::extract_parameters(&self.match_right.as_ref().unwrap(), &context.right.as_ref().unwrap(), &mut env);	
-		}
+		Self::extract_parameters(&self.match_center, context.center.parameter_values, env)?;
 
-		Self::extract_parameters(&self.match_center, &context.center, &mut env);	
+		if let (Some(signature), Some(module)) = (&self.match_right, context.right) {
+			Self::extract_parameters(signature, module.parameter_values, env)?;
+		}
 
-		return env;
+		Ok(())
 	}
 
-	fn extract_parameters(signature: &ModuleSignature, module: &Module, env: &mut Environment) {
+	fn extract_parameters(signature: &ModuleSignature, values: &[f64], env: &mut Environment) -> std::result::Result<(), EvaluationError> {
 		// We expect both the signature and the module to have the same number of parameters.
-		for (i, p) in signature.parameters.iter().enumerate() {
-			let value = module.parameter_values[i];
-			env.define_parameter(*p, value);	
+		for (p, value) in signature.parameters.iter().zip(values.iter()) {
+			env.define_parameter(*p, *value)?;
 		}
+
+		Ok(())
 	}
 }
 
 impl Display for ModulePattern {
 	fn fmt(&self, f: &mut Formatter<'_>) -> Result {
 
-		if(self.match_left.is_some()) {
-			write!(f, "{} < ", self.match_left.as_ref().unwrap())?;
+		if let Some(match_left) = &self.match_left {
+			write!(f, "{} < ", match_left)?;
 		}
 	
 		write!(f, "{}", self.match_center)?;
 		
-		if(self.match_right.is_some()) {
-			write!(f, " > {} ", self.match_right.as_ref().unwrap())?;
+		if let Some(match_right) = &self.match_right {
+			write!(f, " > {} ", match_right)?;
 		}
 
 		match self.condition {
@@ -538,6 +678,7 @@ impl Display for ModulePattern {
 
 /// A rule consisting of a left side pattern and a right side sequence of templates
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Rule {
 	pub pattern: ModulePattern,
 	pub right_side: Vec<ModuleTemplate>,
@@ -546,7 +687,7 @@ pub struct Rule {
 
 impl Rule {
 	/// Checks whether this rule always applies, i.e. has "100% chance". Note that this is not the same as having a probability
-	/// of 1.0, since the probability is more like a general weight, e.g. users could use integers aswell.	
+	/// of 1.0, since the probability is more like a general weight, e.g. users could use integers aswell.
 	pub fn is_deterministic(&self) -> bool {
 		self.probability < 0.0
 	}
@@ -570,13 +711,302 @@ impl Display for Rule {
     }
 }
 
+/// Index mapping module identifiers to the rules whose center pattern could possibly match them.
+/// The rule indices for each identifier are stored in ascending order, which means that the first
+/// matching deterministic rule found through the index is the same as in the plain rule list.
 #[derive(Debug, Clone)]
+struct RuleIndex {
+	candidates: HashMap<char, Vec<usize>>
+}
+
+impl RuleIndex {
+	fn new(rules: &[Rule]) -> RuleIndex {
+		let mut index = RuleIndex{ candidates: HashMap::new() };
+
+		for (i, rule) in rules.iter().enumerate() {
+			index.insert(i, rule);
+		}
+
+		index
+	}
+
+	fn insert(&mut self, position: usize, rule: &Rule) {
+		self.candidates.entry(rule.pattern.match_center.identifier)
+			.or_default()
+			.push(position);
+	}
+
+	/// Retrieve indices of all rules that could match a module with given identifier.
+	fn candidates(&self, identifier: char) -> &[usize] {
+		match self.candidates.get(&identifier) {
+			Some(rules) => rules,
+			None => &[]
+		}
+	}
+}
+
+/// A module template with all parameter expressions compiled.
+#[derive(Debug, Clone)]
+struct CompiledTemplate {
+	identifier: char,
+	annotation: Option<ModuleAnnotation>,
+	parameters: Vec<CompiledExpression>
+}
+
+/// A rule with its condition and right side compiled. The parameters bound by the pattern are assigned
+/// to slots in binding order, which is left, center, right.
+#[derive(Debug, Clone)]
+struct CompiledRule {
+	condition: CompiledExpression,
+	right_side: Vec<CompiledTemplate>
+}
+
+impl CompiledRule {
+	fn new(rule: &Rule) -> std::result::Result<CompiledRule, EvaluationError> {
+		let slots = rule.pattern.bound_parameters();
+
+		for (i, p) in slots.iter().enumerate() {
+			if slots[..i].contains(p) {
+				return Err(EvaluationError::DuplicateParameter(*p));
+			}
+		}
+
+		let mut right_side = Vec::with_capacity(rule.right_side.len());
+
+		for template in &rule.right_side {
+			right_side.push(CompiledTemplate {
+				identifier: template.identifier,
+				annotation: template.annotation,
+				parameters: template.parameter_expressions.iter()
+					.map(|expr| CompiledExpression::arithmetic(expr, &slots))
+					.collect::<std::result::Result<_, _>>()?
+			});
+		}
+
+		Ok(CompiledRule {
+			condition: CompiledExpression::boolean(&rule.pattern.condition, &slots)?,
+			right_side
+		})
+	}
+
+	/// Store the parameter values of given context in given slots. This requires that the context fits the
+	/// pattern of the rule.
+	fn bind(context: &ModuleContext, pattern: &ModulePattern, slots: &mut Vec<f64>) {
+		slots.clear();
+
+		if let (Some(_), Some(left)) = (&pattern.match_left, context.left) {
+			slots.extend_from_slice(left.parameter_values);
+		}
+
+		slots.extend_from_slice(context.center.parameter_values);
+
+		if let (Some(_), Some(right)) = (&pattern.match_right, context.right) {
+			slots.extend_from_slice(right.parameter_values);
+		}
+	}
+
+	fn instantiate(&self, slots: &[f64], stack: &mut Vec<f64>, module_string: &mut ModuleString) {
+		for template in &self.right_side {
+			module_string.push_module(
+				template.identifier,
+				template.annotation,
+				template.parameters.iter().map(|expr| expr.eval(slots, stack))
+			);
+		}
+	}
+}
+
+/// Key of the ChaCha random number generator used for stochastic choices. Since stochastic choices never
+/// advance the generator directly, the key completely determines its state.
+pub type RngKey = [u8; 32];
+
+/// Helper used to obtain the key `SeedableRng::seed_from_u64` derives from a numeric seed.
+struct KeyExpansion(RngKey);
+
+impl SeedableRng for KeyExpansion {
+	type Seed = RngKey;
+
+	fn from_seed(seed: RngKey) -> KeyExpansion {
+		KeyExpansion(seed)
+	}
+}
+
+/// Number of random words reserved for each module position in a generation. This corresponds to
+/// one ChaCha block, which is far more than a single weighted choice consumes.
+const RANDOM_WORDS_PER_MODULE: u128 = 16;
+
+/// Number of modules below which a generation is not split up for parallel iteration.
+#[cfg(feature = "parallel")]
+const MIN_PARALLEL_CHUNK: usize = 4096;
+
+/// Number of modules rewritten between two checks of cancellation, resource limits and progress.
+const CHECK_INTERVAL: usize = 4096;
+
+/// Tracks the derivation of a single generation, enforcing resource limits, observing cancellation
+/// and reporting progress.
+struct DerivationMonitor<'a> {
+	limits: IterationLimits,
+	cancellation: Option<&'a CancellationToken>,
+	progress: Option<&'a ProgressCallback>,
+	/// Time the iteration was started at
+	start: Instant,
+	/// The generation being derived
+	generation: u32,
+	target_generation: u32,
+	total_modules: usize,
+	processed_modules: AtomicUsize,
+	/// Number of modules produced so far, summed over all chunks when rewriting in parallel
+	produced_modules: AtomicUsize,
+	/// Number of parameter values produced so far, summed over all chunks when rewriting in parallel
+	produced_parameters: AtomicUsize
+}
+
+impl<'a> DerivationMonitor<'a> {
+	/// Record that given number of additional modules were rewritten, producing given numbers of additional modules
+	/// and parameter values. Returns the totals of all three counts.
+	fn record(&self, processed: usize, modules: usize, parameters: usize) -> (usize, usize, usize) {
+		(
+			self.processed_modules.fetch_add(processed, Ordering::Relaxed) + processed,
+			self.produced_modules.fetch_add(modules, Ordering::Relaxed) + modules,
+			self.produced_parameters.fetch_add(parameters, Ordering::Relaxed) + parameters
+		)
+	}
+
+	/// Record additional work like `record`, and check whether iteration has to be aborted. The resource limits
+	/// are compared against the totals of the generation, even when rewriting in parallel.
+	fn check(&self, processed: usize, modules: usize, parameters: usize) -> std::result::Result<(), LSystemError> {
+		let (processed, modules, parameters) = self.record(processed, modules, parameters);
+
+		if let Some(token) = self.cancellation {
+			if token.is_cancelled() {
+				return Err(LSystemError::Cancelled{ generation: self.generation });
+			}
+		}
+
+		let elapsed = self.start.elapsed();
+
+		let exceeded = if self.limits.max_modules.is_some_and(|max| modules > max) {
+			Some(Limit::ModuleCount)
+		} else if self.limits.max_parameters.is_some_and(|max| parameters > max) {
+			Some(Limit::ParameterCount)
+		} else if self.limits.max_duration.is_some_and(|max| elapsed > max) {
+			Some(Limit::Duration)
+		} else {
+			None
+		};
+
+		if let Some(limit) = exceeded {
+			return Err(LSystemError::LimitExceeded{ limit, generation: self.generation });
+		}
+
+		if let Some(progress) = self.progress {
+			progress.call(&IterationProgress {
+				generation: self.generation,
+				target_generation: self.target_generation,
+				processed_modules: processed,
+				total_modules: self.total_modules,
+				elapsed
+			});
+		}
+
+		Ok(())
+	}
+}
+
+/// Buffers used while rewriting modules, kept around to avoid allocations in the inner loop.
+#[derive(Default)]
+struct RewriteScratch {
+	matching_rules: Vec<usize>,
+	weighted_rules: Vec<Weighted<usize>>,
+	slots: Vec<f64>,
+	stack: Vec<f64>
+}
+
+/// Engine deriving module strings from an axiom and a rule set. When serialized, the rule set, the derived
+/// generations and the key of the random number generator are stored, which means that a deserialized engine
+/// continues the derivation exactly where the original left off. Compiled rules are rebuilt on deserialization;
+/// cancellation token and progress callback are not serialized.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(from = "IterationEngineState"))]
 pub struct IterationEngine {
-	pub axiom: Vec<Module>,
-	pub module_string: Vec<Module>,
-	pub rules: Vec<Rule>,
 	pub iteration_depth: u32,
-	rng: StdRng
+	axiom: ModuleString,
+	/// The most recently derived generation
+	module_string: ModuleString,
+	/// Index of the generation stored in `module_string`
+	generation: u32,
+	/// All generations preceding the current one, if history is enabled
+	history: Vec<ModuleString>,
+	/// Whether derived generations are retained
+	keep_history: bool,
+	/// Whether the current generation was derived with the current axiom, rule set and seed. If not,
+	/// the next derivation has to start over from the axiom.
+	derivation_valid: bool,
+	rules: Vec<Rule>,
+	#[cfg_attr(feature = "serde", serde(skip))]
+	rule_index: RuleIndex,
+	/// Compiled form of each rule, or the reason it could not be compiled
+	#[cfg_attr(feature = "serde", serde(skip))]
+	compiled_rules: Vec<std::result::Result<CompiledRule, EvaluationError>>,
+	/// Key the random number generator was created with
+	rng_key: RngKey,
+	/// Random number generator created from the key. Stochastic choices never advance it directly;
+	/// instead, every module position in every generation uses its own part of the key stream.
+	/// This makes the result independent of the order in which modules are rewritten.
+	#[cfg_attr(feature = "serde", serde(skip))]
+	rng: ChaCha20Rng,
+	/// Whether generations are rewritten using multiple threads
+	parallel: bool,
+	limits: IterationLimits,
+	#[cfg_attr(feature = "serde", serde(skip))]
+	cancellation: Option<CancellationToken>,
+	#[cfg_attr(feature = "serde", serde(skip))]
+	progress: Option<ProgressCallback>
+}
+
+/// Serialized state of an iteration engine. The fields have to be kept in the same order as the
+/// serialized fields of `IterationEngine`.
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+struct IterationEngineState {
+	iteration_depth: u32,
+	axiom: ModuleString,
+	module_string: ModuleString,
+	generation: u32,
+	history: Vec<ModuleString>,
+	keep_history: bool,
+	derivation_valid: bool,
+	rules: Vec<Rule>,
+	rng_key: RngKey,
+	parallel: bool,
+	limits: IterationLimits
+}
+
+#[cfg(feature = "serde")]
+impl From<IterationEngineState> for IterationEngine {
+	fn from(state: IterationEngineState) -> IterationEngine {
+		let mut engine = IterationEngine::new();
+
+		engine.set_rules(state.rules);
+		engine.set_rng_key(state.rng_key);
+		engine.iteration_depth = state.iteration_depth;
+		engine.axiom = state.axiom;
+		engine.module_string = state.module_string;
+		engine.generation = state.generation;
+		engine.history = state.history;
+		engine.keep_history = state.keep_history;
+		engine.derivation_valid = state.derivation_valid;
+		engine.parallel = state.parallel;
+		engine.limits = state.limits;
+
+		engine
+	}
+}
+
+impl Default for IterationEngine {
+	fn default() -> IterationEngine {
+		IterationEngine::new()
+	}
 }
 
 impl IterationEngine {
@@ -584,82 +1014,754 @@ impl IterationEngine {
 		self.iteration_depth = depth;		
 	}
 
+	/// Add a rule to the end of the rule set. The rule is compiled immediately.
 	pub fn add_rule(&mut self, rule: Rule) {
+		self.rule_index.insert(self.rules.len(), &rule);
+		self.compiled_rules.push(CompiledRule::new(&rule));
 		self.rules.push(rule);	
+		self.derivation_valid = false;
+	}
+
+	/// Replace the whole rule set. All rules are compiled immediately.
+	pub fn set_rules(&mut self, rules: Vec<Rule>) {
+		self.rule_index = RuleIndex::new(&rules);
+		self.compiled_rules = rules.iter().map(CompiledRule::new).collect();
+		self.rules = rules;
+		self.derivation_valid = false;
+	}
+
+	/// Remove all rules.
+	pub fn clear_rules(&mut self) {
+		self.set_rules(Vec::new());
+	}
+
+	/// Retrieve the rule set, in the order the rules were added.
+	pub fn rules(&self) -> &[Rule] {
+		&self.rules
+	}
+
+	/// Set the module string the derivation starts with.
+	pub fn set_axiom(&mut self, axiom: ModuleString) {
+		self.axiom = axiom;
+		self.derivation_valid = false;
+	}
+
+	/// Retrieve the module string the derivation starts with.
+	pub fn axiom(&self) -> &ModuleString {
+		&self.axiom
+	}
+
+	/// Retrieve the most recently derived module string.
+	pub fn module_string(&self) -> &ModuleString {
+		&self.module_string
+	}
+
+	/// Retrieve the index of the generation returned by `module_string`. The axiom is generation 0.
+	pub fn current_generation(&self) -> u32 {
+		self.generation
+	}
+
+	/// Enable or disable retaining all derived generations. If enabled, every generation up to the current one
+	/// can be retrieved using `generation`, and going back to a lower depth does not require starting over from
+	/// the axiom. Disabling history discards all retained generations. Enabling it while generations other than
+	/// the axiom were derived without history causes the next derivation to start over from the axiom.
+	pub fn set_keep_history(&mut self, keep_history: bool) {
+		self.keep_history = keep_history;
+
+		if(!keep_history) {
+			self.history.clear();
+			self.history.shrink_to_fit();
+		} else if(self.history.len() != self.generation as usize) {
+			self.derivation_valid = false;
+		}
+	}
+
+	/// Retrieve the module string of given generation, if it is available. This is always the case for the
+	/// current generation, and for all preceding generations if history is enabled.
+	pub fn generation(&self, generation: u32) -> Option<&ModuleString> {
+		if(generation == self.generation) {
+			Some(&self.module_string)
+		} else {
+			self.history.get(generation as usize)
+		}
 	}
 
 	pub fn new() -> IterationEngine {
+		let rng_key = KeyExpansion::seed_from_u64(133742).0;
+
 		IterationEngine {
-			axiom: Vec::new(),
-			module_string: Vec::new(),
+			axiom: ModuleString::new(),
+			module_string: ModuleString::new(),
+			generation: 0,
+			history: Vec::new(),
+			keep_history: false,
+			derivation_valid: false,
 			rules: Vec::new(),
+			rule_index: RuleIndex::new(&[]),
+			compiled_rules: Vec::new(),
 			iteration_depth: 0,
-			rng: StdRng::seed_from_u64(133742)
+			rng_key,
+			rng: ChaCha20Rng::from_seed(rng_key),
+			parallel: false,
+			limits: IterationLimits::none(),
+			cancellation: None,
+			progress: None
 		}	
 	}
 
+	/// Perform static analysis of the axiom and rule set, reporting problems that would cause
+	/// iteration to fail, as well as rules that most likely do not behave as intended.
+	pub fn validate(&self) -> Vec<Diagnostic> {
+		validate_rules(&self.axiom, &self.rules)
+	}
+
 	pub fn set_seed(&mut self, seed: u64) {
-		self.rng = StdRng::seed_from_u64(seed)
+		self.set_rng_key(KeyExpansion::seed_from_u64(seed).0);
 	}
 
-	pub fn iterate(&mut self) {
+	/// Set the key of the random number generator directly. `set_seed` derives this key from a numeric seed.
+	pub fn set_rng_key(&mut self, key: RngKey) {
+		self.rng_key = key;
+		self.rng = ChaCha20Rng::from_seed(key);
+		self.derivation_valid = false;
+	}
+
+	/// Retrieve the key of the random number generator, which completely determines all stochastic choices.
+	pub fn rng_key(&self) -> RngKey {
+		self.rng_key
+	}
+
+	/// Enable or disable rewriting generations using multiple threads. Parallel and sequential iteration
+	/// produce bit-identical results, including all stochastic choices.
+	#[cfg(feature = "parallel")]
+	pub fn set_parallel(&mut self, parallel: bool) {
+		self.parallel = parallel;
+	}
+
+	/// Set the resource limits enforced during iteration.
+	pub fn set_limits(&mut self, limits: IterationLimits) {
+		self.limits = limits;
+	}
+
+	/// Retrieve the resource limits enforced during iteration.
+	pub fn limits(&self) -> &IterationLimits {
+		&self.limits
+	}
+
+	/// Set the token observed to cancel running iterations. Cancelled iterations fail with `LSystemError::Cancelled`.
+	pub fn set_cancellation_token(&mut self, token: Option<CancellationToken>) {
+		self.cancellation = token;
+	}
+
+	/// Set the callback receiving progress information during iteration.
+	pub fn set_progress_callback(&mut self, callback: Option<ProgressCallback>) {
+		self.progress = callback;
+	}
+
+	/// Iterate the axiom string by repeatedly applying the rule set, until the iteration depth is reached.
+	/// If axiom, rules and seed did not change since the last derivation, this continues from the current
+	/// generation instead of starting over. See `iterate_to`.
+	pub fn iterate(&mut self) -> std::result::Result<(), LSystemError> {
+		self.iterate_to(self.iteration_depth)
+	}
+
+	/// Derive the given generation. If axiom, rules and seed did not change since the last derivation, this
+	/// continues from the current generation, or, if history is enabled, goes back to a retained generation.
+	/// Otherwise, the derivation starts over from the axiom. On failure, the module string remains at the
+	/// last generation that was derived successfully.
+	pub fn iterate_to(&mut self, depth: u32) -> std::result::Result<(), LSystemError> {
+		let start = Instant::now();
+
+		if(!self.derivation_valid || (depth < self.generation && (depth as usize) >= self.history.len())) {
+			self.restart();
+		}
+
+		if(depth < self.generation) {
+			self.history.truncate(depth as usize + 1);
+			self.module_string = self.history.pop().unwrap_or_default();
+			self.generation = depth;
+		}
+
+		while(self.generation < depth) {
+			self.advance(start, depth)?;
+		}
+
+		Ok(())
+	}
+
+	/// Derive the generation following the current one. If axiom, rules or seed changed since the last
+	/// derivation, the axiom is used as the current generation.
+	pub fn step(&mut self) -> std::result::Result<(), LSystemError> {
+		if(!self.derivation_valid) {
+			self.restart();
+		}
+
+		self.advance(Instant::now(), self.generation + 1)
+	}
+
+	/// Derive the generation following the current one, as part of an iteration that was started at given time
+	/// and stops at given generation.
+	fn advance(&mut self, start: Instant, target_generation: u32) -> std::result::Result<(), LSystemError> {
+		let monitor = DerivationMonitor {
+			limits: self.limits,
+			cancellation: self.cancellation.as_ref(),
+			progress: self.progress.as_ref(),
+			start,
+			generation: self.generation + 1,
+			target_generation,
+			total_modules: self.module_string.len(),
+			processed_modules: AtomicUsize::new(0),
+			produced_modules: AtomicUsize::new(0),
+			produced_parameters: AtomicUsize::new(0)
+		};
+
+		let next = self.derive(self.generation, &self.module_string, &monitor)?;
+
+		// Check the complete generation, whose counts have all been recorded, and report that it is done
+		monitor.check(0, 0, 0)?;
+
+		let previous = std::mem::replace(&mut self.module_string, next);
+
+		if(self.keep_history) {
+			self.history.push(previous);
+		}
+
+		self.generation += 1;
+
+		Ok(())
+	}
+
+	/// Discard all derived generations and start over with the axiom.
+	fn restart(&mut self) {
 		self.module_string = self.axiom.clone();
+		self.generation = 0;
+		self.history.clear();
+		self.derivation_valid = true;
+	}
+
+	/// Create a checkpoint of the current derivation state, consisting of the current generation, its index,
+	/// the axiom, the key of the random number generator and, if history is enabled, all preceding generations.
+	/// The checkpoint uses a stable binary format starting with a version header. If axiom, rules or seed changed
+	/// since the last derivation, the checkpoint refers to the axiom. See `restore`.
+	pub fn snapshot(&self) -> Vec<u8> {
+		let mut buffer = Vec::new();
+		self.write_snapshot(&mut buffer).expect("writing to a vector cannot fail");
+		buffer
+	}
 
-		for i in 0..self.iteration_depth {
-			let mut new_module_string: Vec<Module> = Vec::new();
+	/// Write a checkpoint of the current derivation state to given writer. See `snapshot`.
+	pub fn write_snapshot<W: Write>(&self, writer: W) -> io::Result<()> {
+		if(!self.derivation_valid) {
+			return write_checkpoint(writer, &self.rng_key, &self.rules, 0, &self.axiom, &self.axiom, None);
+		}
 
-			for (i, module) in self.module_string.iter().enumerate() {
-				let mut context = ModuleContext::new(module.clone());
-				
-				// If we are not the first module, we can populate the left side of
-				// the context
-				if(i > 0) {
-					context.left = Some(self.module_string[i-1].clone());
-				}
+		let history = if(self.keep_history) { Some(self.history.as_slice()) } else { None };
 
-				// If we are not the last module, there exists a right neighbour.
-				if(i < self.module_string.len() - 1) {
-					context.right = Some(self.module_string[i+1].clone());
-				}
+		write_checkpoint(writer, &self.rng_key, &self.rules, self.generation, &self.axiom, &self.module_string, history)
+	}
 
-				// Collect all rules that match
-				let mut matching_rules = Vec::new();
+	/// Restore the derivation state from a checkpoint created by `snapshot`. The rule set is not part of the
+	/// checkpoint: it has to be set up before restoring, and has to be identical to the one the checkpoint was
+	/// created with. Subsequent derivations continue exactly where the checkpointed engine left off, including
+	/// all stochastic choices. On failure, the engine is left unchanged.
+	pub fn restore(&mut self, checkpoint: &[u8]) -> std::result::Result<(), CheckpointError> {
+		self.read_snapshot(checkpoint)
+	}
+
+	/// Restore the derivation state from a checkpoint read from given reader. See `restore`.
+	pub fn read_snapshot<R: Read>(&mut self, reader: R) -> std::result::Result<(), CheckpointError> {
+		let checkpoint = read_checkpoint(reader, &self.rules)?;
+
+		self.set_rng_key(checkpoint.rng_key);
+		self.axiom = checkpoint.axiom;
+		self.module_string = checkpoint.module_string;
+		self.generation = checkpoint.generation;
+		self.keep_history = checkpoint.history.is_some();
+		self.history = checkpoint.history.unwrap_or_default();
+		self.derivation_valid = true;
+
+		Ok(())
+	}
+
+	/// Create an iterator yielding the modules of the final generation one by one, without ever holding
+	/// the complete module string in memory. The axiom is expanded depth-first, which only works for
+	/// context-free systems: this fails if any rule has a left or right context. The result is identical
+	/// to the module string produced by `iterate`, including stochastic choices. Cancellation and the
+	/// duration limit are observed, measured from the creation of the iterator.
+	pub fn derive_lazily(&self) -> std::result::Result<LazyDerivation<'_>, LSystemError> {
+		if let Some(rule) = self.rules.iter().position(|r| r.pattern.match_left.is_some() || r.pattern.match_right.is_some()) {
+			return Err(LSystemError::ContextSensitiveRule{ rule });
+		}
+
+		Ok(LazyDerivation::new(self))
+	}
+
+	/// Derive the generation following the given one.
+	fn derive(&self, generation: u32, module_string: &ModuleString, monitor: &DerivationMonitor) -> std::result::Result<ModuleString, LSystemError> {
+		#[cfg(feature = "parallel")]
+		{
+			if self.parallel && module_string.len() >= 2 * MIN_PARALLEL_CHUNK {
+				return self.derive_parallel(generation, module_string, monitor);
+			}
+		}
+
+		let mut result = ModuleString::with_capacity(module_string.len(), module_string.parameter_count());
+		self.derive_range(generation, module_string, 0..module_string.len(), monitor, &mut result)?;
+		Ok(result)
+	}
+
+	/// Derive the next generation by splitting the module string into chunks that are rewritten in parallel.
+	/// The derived chunks are concatenated in order, and the first error by module position is reported.
+	#[cfg(feature = "parallel")]
+	fn derive_parallel(&self, generation: u32, module_string: &ModuleString, monitor: &DerivationMonitor) -> std::result::Result<ModuleString, LSystemError> {
+		let chunk_size = (module_string.len() / (4 * rayon::current_num_threads())).max(MIN_PARALLEL_CHUNK);
+
+		let ranges: Vec<std::ops::Range<usize>> = (0..module_string.len())
+			.step_by(chunk_size)
+			.map(|begin| begin..(begin + chunk_size).min(module_string.len()))
+			.collect();
+
+		let chunks: Vec<std::result::Result<ModuleString, LSystemError>> = ranges.into_par_iter()
+			.map(|range| {
+				let mut result = ModuleString::with_capacity(range.len(), 0);
+				self.derive_range(generation, module_string, range, monitor, &mut result)?;
+				Ok(result)
+			})
+			.collect();
+
+		let mut result = ModuleString::new();
+
+		for chunk in chunks {
+			result.extend_from(&chunk?);
+		}
+
+		Ok(result)
+	}
+
+	/// Rewrite the modules in given range of a module string, appending the results to given output.
+	fn derive_range(&self, generation: u32, module_string: &ModuleString, range: std::ops::Range<usize>, monitor: &DerivationMonitor, result: &mut ModuleString) -> std::result::Result<(), LSystemError> {
+		let mut scratch = RewriteScratch::default();
+		let mut rng = self.rng.clone();
+		let mut unchecked = 0;
+
+		// Output counts at the last check, the monitor is only told about the modules produced since
+		let mut modules = result.len();
+		let mut parameters = result.parameter_count();
+
+		for i in range {
+			let context = ModuleContext::at(module_string, i);
+			self.rewrite(&context, generation, i, &mut rng, &mut scratch, result)?;
+
+			unchecked += 1;
 
-				for rule in &self.rules {
-					if(rule.pattern.does_match(&context)) {
-						matching_rules.push(rule.clone());
-					}
+			if(unchecked == CHECK_INTERVAL) {
+				monitor.check(unchecked, result.len() - modules, result.parameter_count() - parameters)?;
+				unchecked = 0;
+				modules = result.len();
+				parameters = result.parameter_count();
+			}
+		}
+
+		monitor.record(unchecked, result.len() - modules, result.parameter_count() - parameters);
+
+		Ok(())
+	}
+
+	/// Apply the rule set to the module with given context, which is located at given position in given generation.
+	/// The resulting modules are appended to given output.
+	fn rewrite(&self, context: &ModuleContext, generation: u32, position: usize, rng: &mut ChaCha20Rng, scratch: &mut RewriteScratch, result: &mut ModuleString) -> std::result::Result<(), LSystemError> {
+		// Collect all candidate rules that actually match
+		scratch.matching_rules.clear();
+
+		for &r in self.rule_index.candidates(context.center.identifier) {
+			let pattern = &self.rules[r].pattern;
+
+			if(!pattern.fits(context)) {
+				continue;
+			}
+
+			let compiled = self.compiled_rules[r].as_ref().map_err(|e| e.at(position, r))?;
+			CompiledRule::bind(context, pattern, &mut scratch.slots);
+
+			if(compiled.condition.eval_bool(&scratch.slots, &mut scratch.stack)) {
+				scratch.matching_rules.push(r);
+			}
+		}
+
+		// If its empty, we can do nothing
+		if(scratch.matching_rules.is_empty()) {
+			result.push(context.center);
+			return Ok(());
+		}
+
+		// Check if there are any rules that are deterministic. If so, apply first one of them.
+		let deterministic_match = scratch.matching_rules.iter()
+			.cloned()
+			.find(|&r| self.rules[r].is_deterministic());
+
+		let index = match deterministic_match {
+			Some(r) => r,
+			None => {
+				scratch.weighted_rules.clear();
+				scratch.weighted_rules.extend(scratch.matching_rules.iter().map(|&r| Weighted{ weight: self.rules[r].probability, item: r }));
+
+				match WeightedChoice::new(&mut scratch.weighted_rules) {
+					Some(wc) => {
+						// Each module position has its own part of the key stream
+						rng.set_stream(generation as u64);
+						rng.set_word_pos(position as u128 * RANDOM_WORDS_PER_MODULE);
+						wc.sample(rng)
+					},
+					None => return Err(LSystemError::ZeroTotalWeight{ module_index: position, rules: scratch.matching_rules.clone() })
 				}
+			}
+		};
+
+		// We now have a match. Instantiate right side.
+		let compiled = self.compiled_rules[index].as_ref().map_err(|e| e.at(position, index))?;
+		CompiledRule::bind(context, &self.rules[index].pattern, &mut scratch.slots);
+		compiled.instantiate(&scratch.slots, &mut scratch.stack, result);
+
+		Ok(())
+	}
+}
+
+/// Iterator over the final generation of a context-free L-System, created by `IterationEngine::derive_lazily`.
+/// Modules are expanded depth-first, so only the pending modules along the current path of the derivation tree
+/// are kept in memory. Every item is either a module of the final generation, in order, or the error that stopped
+/// the derivation.
+pub struct LazyDerivation<'a> {
+	engine: &'a IterationEngine,
+	/// Modules to be expanded for each generation along the current path
+	frames: Vec<ModuleString>,
+	/// Index of the next module to expand in each frame
+	cursors: Vec<usize>,
+	/// Number of modules of each generation that were already visited. Since expansion is depth-first,
+	/// this is the position of the next visited module within its generation.
+	positions: Vec<usize>,
+	/// Frames that were completely consumed, kept to reuse their allocation
+	spare_frames: Vec<ModuleString>,
+	rng: ChaCha20Rng,
+	scratch: RewriteScratch,
+	failed: bool,
+	/// Time the derivation was started at, used to enforce the duration limit
+	start: Instant,
+	/// Number of modules rewritten since cancellation and the duration limit were last checked
+	unchecked: usize
+}
+
+impl<'a> LazyDerivation<'a> {
+	fn new(engine: &'a IterationEngine) -> LazyDerivation<'a> {
+		let depth = engine.iteration_depth as usize;
+
+		LazyDerivation {
+			engine,
+			frames: vec![engine.axiom.clone()],
+			cursors: vec![0],
+			positions: vec![0; depth + 1],
+			spare_frames: Vec::new(),
+			rng: engine.rng.clone(),
+			scratch: RewriteScratch::default(),
+			failed: false,
+			start: Instant::now(),
+			unchecked: 0
+		}
+	}
+
+	/// Check for cancellation and the duration limit. Module and parameter count limits do not apply,
+	/// since lazy derivation never holds a complete generation.
+	fn check(&self, generation: u32) -> std::result::Result<(), LSystemError> {
+		if self.engine.cancellation.as_ref().is_some_and(|token| token.is_cancelled()) {
+			return Err(LSystemError::Cancelled{ generation });
+		}
+
+		if self.engine.limits.max_duration.is_some_and(|max| self.start.elapsed() > max) {
+			return Err(LSystemError::LimitExceeded{ limit: Limit::Duration, generation });
+		}
+
+		Ok(())
+	}
+}
+
+impl<'a> Iterator for LazyDerivation<'a> {
+	type Item = std::result::Result<Module, LSystemError>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		if self.failed {
+			return None;
+		}
+
+		loop {
+			let generation = self.frames.len().checked_sub(1)?;
+			let cursor = self.cursors[generation];
 
-				// If its empty, we can do nothing
-				if(matching_rules.len() == 0) {
-					new_module_string.push(module.clone());
-				} else {
-					// Check if there are any rules that are deterministic. If so, apply first one of them.
-					let deterministic_matches: Vec<Rule> = matching_rules.clone().into_iter()
-						.filter(|r| r.is_deterministic())
-						.collect();
-
-					let chosen_match = match deterministic_matches.len() {
-						0 => {
-							let mut items: Vec<Weighted<Rule>> = matching_rules.into_iter().map(|r| Weighted{ weight: r.probability, item: r }).collect();
-							let wc = WeightedChoice::new(&mut items);
-							
-							wc.sample(&mut self.rng)
-						},
-						_ => deterministic_matches.first().unwrap().clone()
-					};
-
-					// We now have a match. Instantiate right side.
-					let env = chosen_match.pattern.bind(&context);
-
-					for template in &chosen_match.right_side {
-						new_module_string.push(template.instantiate(&env));						
-					}
+			if cursor == self.frames[generation].len() {
+				let mut frame = self.frames.pop().unwrap();
+				frame.clear();
+				self.spare_frames.push(frame);
+				self.cursors.pop();
+				continue;
+			}
+
+			self.cursors[generation] += 1;
+
+			let position = self.positions[generation];
+			self.positions[generation] += 1;
+
+			if generation == self.engine.iteration_depth as usize {
+				return Some(Ok(self.frames[generation].at(cursor).to_module()));
+			}
+
+			self.unchecked += 1;
+
+			if self.unchecked == CHECK_INTERVAL {
+				self.unchecked = 0;
+
+				if let Err(e) = self.check(generation as u32 + 1) {
+					self.failed = true;
+					return Some(Err(e));
 				}
 			}
 
-			self.module_string = new_module_string;
+			let mut children = self.spare_frames.pop().unwrap_or_default();
+
+			let context = ModuleContext::new(self.frames[generation].at(cursor));
+
+			if let Err(e) = self.engine.rewrite(&context, generation as u32, position, &mut self.rng, &mut self.scratch, &mut children) {
+				self.failed = true;
+				return Some(Err(e));
+			}
+
+			self.frames.push(children);
+			self.cursors.push(0);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::annotation::AnnotationRegistry;
+	use crate::grammar::lsystem_parser;
+	use std::sync::{Arc, Mutex};
+
+	/// Derive a stochastic, parametric and context-sensitive system, either sequentially or in parallel.
+	#[cfg(feature = "parallel")]
+	fn derive(parallel: bool) -> IterationEngine {
+		let annotations = AnnotationRegistry::new();
+		let rules = "A(x) : 0.5 -> A(x+1)[+B(x)]A(x*0.5)\n\
+			A(x) : 0.5 -> B(x)A(x-1)A(x)\n\
+			B(x) > A(y) : x > y -> B(x/3)\n\
+			B(x) : x > 2 -> B(x/2)";
+
+		let mut engine = IterationEngine::new();
+		engine.set_axiom(ModuleString::from(lsystem_parser::module_string("A(1)", &annotations).unwrap()));
+		engine.set_rules(lsystem_parser::rule_list(rules, &annotations).unwrap());
+		engine.set_seed(7);
+		engine.set_iteration_depth(14);
+		engine.set_parallel(parallel);
+		engine.iterate().unwrap();
+		engine
+	}
+
+	#[test]
+	#[cfg(feature = "parallel")]
+	fn parallel_derivation_matches_sequential() {
+		let sequential = derive(false);
+		let parallel = derive(true);
+
+		assert!(sequential.module_string().len() >= 2 * MIN_PARALLEL_CHUNK);
+		assert_eq!(sequential.module_string().len(), parallel.module_string().len());
+
+		for (s, p) in sequential.module_string().iter().zip(parallel.module_string().iter()) {
+			assert_eq!((s.identifier, s.annotation), (p.identifier, p.annotation));
+
+			let bits = |values: &[f64]| values.iter().map(|v| v.to_bits()).collect::<Vec<_>>();
+			assert_eq!(bits(s.parameter_values), bits(p.parameter_values));
+		}
+	}
+
+	/// Engine deriving a stochastic, parametric and context-free system.
+	fn context_free() -> IterationEngine {
+		let annotations = AnnotationRegistry::new();
+		let rules = "A(x) : 0.5 -> A(x+1)[+B(x)]A(x*0.5)\n\
+			A(x) : 0.5 -> B(x)A(x-1)\n\
+			B(x) : x > 2 -> B(x/2)";
+
+		let mut engine = IterationEngine::new();
+		engine.set_axiom(ModuleString::from(lsystem_parser::module_string("A(1)B(9)", &annotations).unwrap()));
+		engine.set_rules(lsystem_parser::rule_list(rules, &annotations).unwrap());
+		engine.set_seed(11);
+		engine.set_iteration_depth(9);
+		engine
+	}
+
+	#[test]
+	fn lazy_derivation_matches_iteration() {
+		let mut engine = context_free();
+		let lazy: ModuleString = engine.derive_lazily().unwrap().collect::<std::result::Result<_, _>>().unwrap();
+
+		engine.iterate().unwrap();
+
+		assert!(engine.module_string().len() > 100);
+		assert_eq!(&lazy, engine.module_string());
+	}
+
+	#[test]
+	fn iterating_back_and_forth_matches_fresh_derivation() {
+		let mut engine = context_free();
+		engine.set_keep_history(true);
+
+		engine.iterate_to(8).unwrap();
+		engine.iterate_to(3).unwrap();
+		assert_eq!(engine.current_generation(), 3);
+		engine.iterate_to(9).unwrap();
+
+		let mut fresh = context_free();
+		fresh.iterate_to(9).unwrap();
+
+		assert_eq!(engine.current_generation(), 9);
+		assert_eq!(engine.module_string(), fresh.module_string());
+	}
+
+	#[test]
+	#[cfg(feature = "serde")]
+	fn serialized_engines_continue_identically() {
+		use crate::interpretation::InterpretationEngine;
+		use crate::drawing::DrawingParameters;
+
+		let mut engine = context_free();
+		engine.iterate_to(5).unwrap();
+
+		let mut interpretation = InterpretationEngine::standard();
+		interpretation.parse_mapping("A(x) => F(x*step) +(30)").unwrap();
+		interpretation.parse_mapping("B(x) => [ -(x) F(1) ]").unwrap();
+
+		let mut restored: IterationEngine = serde_json::from_str(&serde_json::to_string(&engine).unwrap()).unwrap();
+		let restored_interpretation: InterpretationEngine = serde_json::from_str(&serde_json::to_string(&interpretation).unwrap()).unwrap();
+
+		engine.iterate_to(9).unwrap();
+		restored.iterate_to(9).unwrap();
+		assert_eq!(restored.module_string(), engine.module_string());
+
+		let parameters = DrawingParameters::new();
+		let commands = interpretation.interpret(engine.module_string(), &parameters).unwrap();
+		let restored_commands = restored_interpretation.interpret(restored.module_string(), &parameters).unwrap();
+
+		assert!(commands.len() > engine.module_string().len());
+		assert_eq!(format!("{:?}", restored_commands), format!("{:?}", commands));
+	}
+
+	/// Length of generation n of `F -> FF+FF` starting with `F`: 4^n modules F and (4^n - 1) / 3 modules +.
+	fn doubling_length(generation: u32) -> usize {
+		let f = 4usize.pow(generation);
+		f + (f - 1) / 3
+	}
+
+	/// Engines deriving the given rules from the given axiom, once sequentially and, if available, once in parallel.
+	fn engines(axiom: &str, rules: &str, depth: u32) -> Vec<IterationEngine> {
+		let annotations = AnnotationRegistry::new();
+
+		let mut engine = IterationEngine::new();
+		engine.set_axiom(ModuleString::from(lsystem_parser::module_string(axiom, &annotations).unwrap()));
+		engine.set_rules(lsystem_parser::rule_list(rules, &annotations).unwrap());
+		engine.set_iteration_depth(depth);
+
+		#[cfg(feature = "parallel")]
+		{
+			let mut parallel = engine.clone();
+			parallel.set_parallel(true);
+			return vec![engine, parallel];
+		}
+
+		#[cfg(not(feature = "parallel"))]
+		vec![engine]
+	}
+
+	/// Install a progress callback recording all reports.
+	fn record_progress(engine: &mut IterationEngine) -> Arc<Mutex<Vec<IterationProgress>>> {
+		let reports = Arc::new(Mutex::new(Vec::new()));
+		let target = reports.clone();
+
+		engine.set_progress_callback(Some(ProgressCallback::new(move |p| target.lock().unwrap().push(*p))));
+		reports
+	}
+
+	#[test]
+	fn limits_abort_generation_early() {
+		let cases = [
+			("F", "F -> FF+FF", IterationLimits{ max_modules: Some(36000), ..IterationLimits::none() }, Limit::ModuleCount),
+			("F(1)", "F(x) -> F(x)F(x)+F(x)F(x)", IterationLimits{ max_parameters: Some(28000), ..IterationLimits::none() }, Limit::ParameterCount)
+		];
+
+		for (axiom, rules, limits, limit) in &cases {
+			for mut engine in engines(axiom, rules, 8) {
+				engine.set_limits(*limits);
+				let reports = record_progress(&mut engine);
+
+				assert_eq!(engine.iterate(), Err(LSystemError::LimitExceeded{ limit: *limit, generation: 8 }));
+				assert_eq!(engine.current_generation(), 7);
+				assert_eq!(engine.module_string().len(), doubling_length(7));
+
+				// Each rewritten module produces about four modules and three parameter values, so the limit is
+				// exceeded by the output of less than half of generation 7, summed over all chunks
+				let reports = reports.lock().unwrap();
+				assert!(reports.iter().any(|p| p.generation == 8));
+				assert!(reports.iter().filter(|p| p.generation == 8).all(|p| p.processed_modules < p.total_modules / 2));
+			}
+		}
+	}
+
+	#[test]
+	fn cancellation_aborts_iteration() {
+		for mut engine in engines("F", "F -> FF+FF", 8) {
+			let token = CancellationToken::new();
+			engine.set_cancellation_token(Some(token.clone()));
+
+			token.cancel();
+			assert_eq!(engine.iterate(), Err(LSystemError::Cancelled{ generation: 1 }));
+			assert_eq!(engine.current_generation(), 0);
+
+			// Cancel while generation 8 is being derived
+			token.reset();
+			let cancel = token.clone();
+			engine.set_progress_callback(Some(ProgressCallback::new(move |p| {
+				if(p.generation == 8) {
+					cancel.cancel();
+				}
+			})));
+
+			assert_eq!(engine.iterate(), Err(LSystemError::Cancelled{ generation: 8 }));
+			assert_eq!(engine.current_generation(), 7);
+
+			token.reset();
+			engine.set_progress_callback(None);
+			engine.iterate().unwrap();
+			assert_eq!(engine.module_string().len(), doubling_length(8));
+		}
+	}
+
+	#[test]
+	fn progress_reports_every_generation() {
+		for mut engine in engines("F", "F -> FF+FF", 8) {
+			let reports = record_progress(&mut engine);
+			engine.iterate().unwrap();
+
+			let reports = reports.lock().unwrap();
+			assert!(reports.windows(2).all(|w| w[0].generation <= w[1].generation));
+			assert!(reports.iter().all(|p| p.target_generation == 8 && p.processed_modules <= p.total_modules));
+
+			// Generation 8 is large enough to be reported on while it is derived
+			assert!(reports.iter().any(|p| p.generation == 8 && p.processed_modules < p.total_modules));
+
+			// The last report of each generation is sent once it is complete
+			for generation in 1..=8 {
+				let last = reports.iter().rev().find(|p| p.generation == generation).unwrap();
+
+				assert_eq!(last.total_modules, doubling_length(generation - 1));
+				assert_eq!(last.processed_modules, last.total_modules);
+				assert_eq!(last.generation_fraction(), 1.0);
+			}
 		}
 	}
 }
@@ -1,3 +1,7 @@
+// The code base intentionally uses a C-like style (parenthesized conditions, explicit returns,
+// single letter matrix names), so the corresponding lints are disabled crate-wide.
+#![allow(unused_parens, non_snake_case, clippy::needless_return)]
+
 // All features used for drawing, i.e. turtle implementation and primtive defintions
 pub mod drawing;
 // Iteration engine and support structures
@@ -8,25 +12,42 @@ pub mod grammar;
 pub mod util;
 // Interpretation of interated module strings
 pub mod interpretation;
+// Static analysis of rule sets
+pub mod validation;
+// Error type shared by iteration and interpretation
+pub mod error;
+// Compilation of rule expressions into flat programs
+pub mod compiler;
+// Resource limits, progress reporting and cancellation of iteration
+pub mod limits;
+// Compact storage of module strings
+pub mod module_string;
+// Binary checkpoints of the iteration state
+pub mod checkpoint;
+// Registry of module annotation prefixes
+pub mod annotation;
 
 
 use crate::drawing::*;
 use crate::drawing::turtle::*;
+use crate::drawing::custom::*;
 use crate::iteration::*;
-use crate::grammar::*;
 use crate::interpretation::*;
+use crate::validation::*;
+use crate::error::*;
+use crate::module_string::*;
 
 
 /// Top level structure providing the means of describing, iterating, interpreting and drawing of an L-System.
 ///
 /// The user code has to perform the following steps to receive a list of primitives used to draw the L-System:
-/// 	- Construct a new L-System instance
-/// 	- Call `parse` with an axiom string and a set of rules
-/// 	- Set interpretation associations in `interpretation_engine`
-/// 	- Set drawing parameters and iteration depth
-/// 	- Call `iterate`
-/// 	- Call `interpret`
-/// 	- Use the primitives saved in `drawing_result` to display the L-System in a platform dependent way
+///   - Construct a new L-System instance
+///   - Call `parse` with an axiom string and a set of rules
+///   - Set interpretation associations in `interpretation_engine`
+///   - Set drawing parameters and iteration depth
+///   - Call `iterate`
+///   - Call `interpret`
+///   - Use the primitives saved in `drawing_result` to display the L-System in a platform dependent way
 /// 
 /// Note that depending on the application, when redrawing a modified system, not all of the above steps have to be
 /// repeated. This can significantly speed up the process. For example, if only associations changed, a call to interpret 
@@ -40,8 +61,18 @@ pub struct LSystem {
 	pub parameters: DrawingParameters,
 	/// Drawing commands as the result of the interpretation stage
 	pub commands: Vec<DrawingCommand>,
+	/// Report about modules that were not interpreted during the interpretation stage
+	pub interpretation_report: InterpretationReport,
 	/// Struct containing all primitives generated during drawing stage  
-	pub drawing_result: DrawingResult
+	pub drawing_result: DrawingResult,
+	/// Application defined operations, executed for modules associated with custom commands, see `InterpretationEngine::associate_custom`
+	pub custom_commands: CustomCommandRegistry
+}
+
+impl Default for LSystem {
+	fn default() -> LSystem {
+		LSystem::new()
+	}
 }
 
 impl LSystem {
@@ -52,25 +83,84 @@ impl LSystem {
 
 	/// Perform L-System iteration by applying ruleset to axiom string and creating a derived
 	/// module string
-	pub fn iterate(&mut self) {
-		self.iteration_engine.iterate();
+	pub fn iterate(&mut self) -> Result<(), LSystemError> {
+		self.iteration_engine.iterate()
 	}
 
 	/// Interpret generated module string as sequence of drawing commands
-	pub fn interpret(&mut self) {
-		self.commands = self.interpretation_engine.interpret(&self.iteration_engine.module_string);
+	pub fn interpret(&mut self) -> Result<(), LSystemError> {
+		let (commands, report) = self.interpretation_engine.interpret_with_report(self.iteration_engine.module_string(), &self.parameters)?;
+		self.commands = commands;
+		self.interpretation_report = report;
 
-		let mut turtle = Turtle3D::new(self.parameters, self.iteration_engine.iteration_depth);
+		let mut turtle = Turtle3D::new(self.parameters, self.iteration_engine.current_generation());
+		turtle.set_custom_commands(std::mem::take(&mut self.custom_commands));
 
 		turtle.execute_modules(&self.commands);
 
+		self.custom_commands = turtle.take_custom_commands();
 		self.drawing_result = turtle.retrieve_result().clone();
+
+		Ok(())
 	}
 
-	/// Parse given axiom string and rule set
+	/// Iterate and interpret the system in a single pass, without ever holding the complete module string or
+	/// command list in memory. Modules are derived lazily and immediately interpreted and drawn, which allows
+	/// drawing deep context-free systems whose module strings would not fit into memory. Only `drawing_result`
+	/// and `interpretation_report` are populated; `commands` is cleared, and the module string of the iteration engine is left untouched.
+	pub fn interpret_lazily(&mut self) -> Result<(), LSystemError> {
+		self.commands.clear();
+
+		let mut turtle = Turtle3D::new(self.parameters, self.iteration_engine.iteration_depth);
+		turtle.set_custom_commands(std::mem::take(&mut self.custom_commands));
+
+		let mut report = InterpretationReport::new();
+		let mut context = InterpretationContext::new(&self.parameters);
+		let result = Self::draw_lazily(&self.iteration_engine, &self.interpretation_engine, &mut context, &mut turtle, &mut report);
+
+		// The custom commands have to be returned even if drawing failed
+		self.custom_commands = turtle.take_custom_commands();
+		result?;
+
+		self.interpretation_report = report;
+		self.drawing_result = turtle.retrieve_result().clone();
+
+		Ok(())
+	}
+
+	fn draw_lazily(iteration_engine: &IterationEngine, interpretation_engine: &InterpretationEngine, context: &mut InterpretationContext, turtle: &mut Turtle3D, report: &mut InterpretationReport) -> Result<(), LSystemError> {
+		let mut commands = Vec::new();
+
+		for (i, module) in iteration_engine.derive_lazily()?.enumerate() {
+			commands.clear();
+			interpretation_engine.interpret_module(module?.view(), i, context, &mut commands, report)?;
+			turtle.execute_modules(&commands);
+		}
+
+		Ok(())
+	}
+
+	/// Perform static analysis of the rule set and the interpretation associations. The returned
+	/// diagnostics contain both problems that would cause iteration or interpretation to fail and
+	/// warnings about rules and symbols that most likely do not behave as intended.
+	pub fn validate(&self) -> Vec<Diagnostic> {
+		let mut diagnostics = self.iteration_engine.validate();
+
+		diagnostics.extend(validate_interpretation(
+			self.iteration_engine.axiom(),
+			self.iteration_engine.rules(),
+			&self.interpretation_engine
+		));
+
+		diagnostics
+	}
+
+	/// Parse given axiom string and rule set, recognizing the annotation prefixes registered with the interpretation engine
 	pub fn parse(&mut self, axiom: &str, rules: &str) {
-		self.iteration_engine.axiom = grammar::lsystem_parser::module_string(axiom).unwrap_or(Vec::new());
-		self.iteration_engine.rules = grammar::lsystem_parser::rule_list(rules).unwrap_or(Vec::new());
+		let annotations = self.interpretation_engine.annotations();
+
+		self.iteration_engine.set_axiom(ModuleString::from(grammar::lsystem_parser::module_string(axiom, annotations).unwrap_or_default()));
+		self.iteration_engine.set_rules(grammar::lsystem_parser::rule_list(rules, annotations).unwrap_or_default());
 	}
 
 	/// Create new, empty L-System.
@@ -80,7 +170,9 @@ impl LSystem {
 			interpretation_engine: InterpretationEngine::new(),
 			parameters: DrawingParameters::new(),
 			commands: Vec::new(),
-			drawing_result: DrawingResult::new()
+			interpretation_report: InterpretationReport::new(),
+			drawing_result: DrawingResult::new(),
+			custom_commands: CustomCommandRegistry::new()
 		}
 	}
 
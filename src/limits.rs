@@ -0,0 +1,121 @@
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+#[cfg(feature = "serde")]
+use serde_derive::*;
+
+/// Resource limits imposed on iteration. Exceeding any of them aborts the derivation with
+/// `LSystemError::LimitExceeded`. Limits that are `None` are not enforced.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct IterationLimits {
+	/// Maximum number of modules in a derived module string.
+	pub max_modules: Option<usize>,
+	/// Maximum total number of parameter values in a derived module string.
+	pub max_parameters: Option<usize>,
+	/// Maximum wall-clock time a single call to `iterate`, `iterate_to` or `step` may take.
+	pub max_duration: Option<Duration>
+}
+
+impl IterationLimits {
+	/// Create limits that do not restrict iteration at all.
+	pub fn none() -> IterationLimits {
+		IterationLimits::default()
+	}
+}
+
+/// The resource limit that caused an iteration to be aborted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Limit {
+	ModuleCount,
+	ParameterCount,
+	Duration
+}
+
+impl fmt::Display for Limit {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Limit::ModuleCount => write!(f, "module count"),
+			Limit::ParameterCount => write!(f, "parameter count"),
+			Limit::Duration => write!(f, "duration")
+		}
+	}
+}
+
+/// A token used to cancel a running iteration from another thread. Clones of a token share their state,
+/// which means that cancelling any of them cancels all iterations observing one of the clones.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+	cancelled: Arc<AtomicBool>
+}
+
+impl CancellationToken {
+	pub fn new() -> CancellationToken {
+		CancellationToken::default()
+	}
+
+	/// Request cancellation of all iterations observing this token.
+	pub fn cancel(&self) {
+		self.cancelled.store(true, Ordering::Relaxed);
+	}
+
+	/// Check whether cancellation was requested.
+	pub fn is_cancelled(&self) -> bool {
+		self.cancelled.load(Ordering::Relaxed)
+	}
+
+	/// Withdraw a cancellation request, allowing the token to be used for further iterations.
+	pub fn reset(&self) {
+		self.cancelled.store(false, Ordering::Relaxed);
+	}
+}
+
+/// Snapshot of the state of a running iteration, passed to the progress callback.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IterationProgress {
+	/// The generation currently being derived.
+	pub generation: u32,
+	/// The generation the iteration will stop at.
+	pub target_generation: u32,
+	/// Number of modules of the preceding generation that were already rewritten.
+	pub processed_modules: usize,
+	/// Total number of modules in the preceding generation.
+	pub total_modules: usize,
+	/// Time passed since the iteration was started.
+	pub elapsed: Duration
+}
+
+impl IterationProgress {
+	/// Retrieve the fraction of the current generation that was already derived, in the range [0, 1].
+	pub fn generation_fraction(&self) -> f64 {
+		if(self.total_modules == 0) {
+			1.0
+		} else {
+			self.processed_modules as f64 / self.total_modules as f64
+		}
+	}
+}
+
+/// Callback invoked with progress information during iteration. It is called once a generation has been
+/// derived completely, and periodically while a generation is being derived. With parallel iteration enabled,
+/// it may be called from multiple threads concurrently.
+#[derive(Clone)]
+pub struct ProgressCallback(Arc<dyn Fn(&IterationProgress) + Send + Sync>);
+
+impl ProgressCallback {
+	pub fn new<F: Fn(&IterationProgress) + Send + Sync + 'static>(callback: F) -> ProgressCallback {
+		ProgressCallback(Arc::new(callback))
+	}
+
+	pub fn call(&self, progress: &IterationProgress) {
+		(self.0)(progress)
+	}
+}
+
+impl fmt::Debug for ProgressCallback {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "ProgressCallback")
+	}
+}
@@ -0,0 +1,268 @@
+use std::fmt::*;
+use std::iter::FromIterator;
+use crate::iteration::*;
+use crate::annotation::*;
+#[cfg(feature = "serde")]
+use serde_derive::*;
+#[cfg(feature = "serde")]
+use serde::{Serialize, Serializer};
+
+/// A borrowed view of a single module, either stored in a `ModuleString` or in a `Module`.
+/// Views are cheap to copy and are used for pattern matching and interpretation.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct ModuleView<'a> {
+	/// The character used as the identifier of this module.
+	pub identifier: char,
+	/// The actual parameters values.
+	pub parameter_values: &'a [f64],
+	/// A possible module annotation.
+	pub annotation: Option<ModuleAnnotation>
+}
+
+impl<'a> ModuleView<'a> {
+	pub fn has_parameters(& self) -> bool {
+		return !self.parameter_values.is_empty();
+	}
+
+	pub fn parameter_count(& self) -> usize {
+		return self.parameter_values.len();
+	}
+
+	pub fn has_annotation(& self) -> bool {
+		return self.annotation.is_some();
+	}
+
+	/// Display this module using the annotation prefixes of given registry. See `ModuleString::display_with`.
+	pub fn display_with(self, annotations: &'a AnnotationRegistry) -> AnnotatedDisplay<'a, ModuleView<'a>> {
+		AnnotatedDisplay { value: self, annotations: Some(annotations) }
+	}
+
+	/// Create an owned copy of the viewed module.
+	pub fn to_module(& self) -> Module {
+		Module {
+			identifier: self.identifier,
+			parameter_values: self.parameter_values.to_vec(),
+			annotation: self.annotation
+		}
+	}
+}
+
+impl<'a> Display for ModuleView<'a> {
+	fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+		write!(f, "{}", AnnotatedDisplay { value: *self, annotations: None })
+	}
+}
+
+/// Display adapter that writes annotations using the prefixes registered with an annotation registry, falling
+/// back to the generic notation `@{id}` for unregistered custom annotations. Created by `display_with`.
+pub struct AnnotatedDisplay<'a, T> {
+	value: T,
+	annotations: Option<&'a AnnotationRegistry>
+}
+
+impl<'a> Display for AnnotatedDisplay<'a, ModuleView<'a>> {
+	fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+		let module = &self.value;
+
+		if let Some(annotation) = module.annotation {
+			match self.annotations.and_then(|a| a.prefix(annotation)) {
+				Some(prefix) => write!(f, "{}", prefix)?,
+				None => write!(f, "{}", annotation)?
+			}
+		}
+
+		write!(f, "{}", module.identifier)?;
+
+		if(module.has_parameters()) {
+			write!(f, "(")?;
+
+			for (i, p) in module.parameter_values.iter().enumerate() {
+				if(i > 0) {
+					write!(f, ",")?;
+				}
+
+				write!(f, "{}", p)?;
+			}
+
+			write!(f, ")")?;
+		}
+
+		Ok(())
+	}
+}
+
+impl<'a> Display for AnnotatedDisplay<'a, &'a ModuleString> {
+	fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+		for module in self.value.iter() {
+			let display = AnnotatedDisplay { value: module, annotations: self.annotations };
+			write!(f, "{}", display)?;
+		}
+
+		Ok(())
+	}
+}
+
+/// A sequence of modules stored as a structure of arrays. Identifiers and annotations are stored in
+/// separate arrays, and the parameter values of all modules share a single buffer, which makes strings of
+/// mostly parameterless modules take up only a few bytes per module. Modules are accessed through `ModuleView`.
+/// When serialized, a module string is represented as a sequence of modules.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Deserialize), serde(from = "Vec<Module>"))]
+pub struct ModuleString {
+	identifiers: Vec<char>,
+	annotations: Vec<Option<ModuleAnnotation>>,
+	/// Start of the parameter values of each module in `parameters`, followed by the total number of
+	/// parameter values. This always contains one entry more than there are modules.
+	offsets: Vec<usize>,
+	parameters: Vec<f64>
+}
+
+impl ModuleString {
+	/// Create a new, empty module string.
+	pub fn new() -> ModuleString {
+		ModuleString::with_capacity(0, 0)
+	}
+
+	/// Display this module string using the annotation prefixes of given registry, such that it can be parsed back
+	/// using the same registry. The plain `Display` implementation writes application defined annotations in the
+	/// generic notation `@{id}` instead.
+	pub fn display_with<'a>(&'a self, annotations: &'a AnnotationRegistry) -> AnnotatedDisplay<'a, &'a ModuleString> {
+		AnnotatedDisplay { value: self, annotations: Some(annotations) }
+	}
+
+	/// Create a new, empty module string with room for given number of modules and parameter values.
+	pub fn with_capacity(modules: usize, parameters: usize) -> ModuleString {
+		let mut offsets = Vec::with_capacity(modules + 1);
+		offsets.push(0);
+
+		ModuleString {
+			identifiers: Vec::with_capacity(modules),
+			annotations: Vec::with_capacity(modules),
+			offsets,
+			parameters: Vec::with_capacity(parameters)
+		}
+	}
+
+	/// Retrieve the number of modules in this string.
+	pub fn len(& self) -> usize {
+		self.identifiers.len()
+	}
+
+	pub fn is_empty(& self) -> bool {
+		self.identifiers.is_empty()
+	}
+
+	/// Retrieve the total number of parameter values of all modules in this string.
+	pub fn parameter_count(& self) -> usize {
+		self.parameters.len()
+	}
+
+	/// Retrieve the module at given index, if it exists.
+	pub fn get(& self, index: usize) -> Option<ModuleView<'_>> {
+		if(index < self.len()) {
+			Some(self.at(index))
+		} else {
+			None
+		}
+	}
+
+	/// Retrieve the module at given index. Panics if the index is out of bounds.
+	pub fn at(& self, index: usize) -> ModuleView<'_> {
+		ModuleView {
+			identifier: self.identifiers[index],
+			parameter_values: &self.parameters[self.offsets[index]..self.offsets[index + 1]],
+			annotation: self.annotations[index]
+		}
+	}
+
+	/// Iterate over all modules in this string, in order.
+	pub fn iter(& self) -> impl DoubleEndedIterator<Item = ModuleView<'_>> + ExactSizeIterator + '_ {
+		(0..self.len()).map(move |i| self.at(i))
+	}
+
+	/// Append a copy of given module.
+	pub fn push(&mut self, module: ModuleView) {
+		self.push_module(module.identifier, module.annotation, module.parameter_values.iter().cloned());
+	}
+
+	/// Append a module with given identifier, annotation and parameter values.
+	pub fn push_module<I: IntoIterator<Item = f64>>(&mut self, identifier: char, annotation: Option<ModuleAnnotation>, parameters: I) {
+		self.identifiers.push(identifier);
+		self.annotations.push(annotation);
+		self.parameters.extend(parameters);
+		self.offsets.push(self.parameters.len());
+	}
+
+	/// Append all modules of given module string.
+	pub fn extend_from(&mut self, other: &ModuleString) {
+		let base = self.parameters.len();
+
+		self.identifiers.extend_from_slice(&other.identifiers);
+		self.annotations.extend_from_slice(&other.annotations);
+		self.offsets.extend(other.offsets[1..].iter().map(|offset| base + offset));
+		self.parameters.extend_from_slice(&other.parameters);
+	}
+
+	/// Remove all modules, keeping the allocated storage.
+	pub fn clear(&mut self) {
+		self.identifiers.clear();
+		self.annotations.clear();
+		self.offsets.truncate(1);
+		self.parameters.clear();
+	}
+
+	/// Create owned copies of all modules in this string.
+	pub fn to_modules(& self) -> Vec<Module> {
+		self.iter().map(|m| m.to_module()).collect()
+	}
+}
+
+#[cfg(feature = "serde")]
+impl Serialize for ModuleString {
+	fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+		serializer.collect_seq(self.iter())
+	}
+}
+
+impl Default for ModuleString {
+	fn default() -> ModuleString {
+		ModuleString::new()
+	}
+}
+
+impl<'a> From<&'a [Module]> for ModuleString {
+	fn from(modules: &'a [Module]) -> ModuleString {
+		let mut module_string = ModuleString::with_capacity(modules.len(), modules.iter().map(|m| m.parameter_count()).sum());
+
+		for module in modules {
+			module_string.push(module.view());
+		}
+
+		module_string
+	}
+}
+
+impl From<Vec<Module>> for ModuleString {
+	fn from(modules: Vec<Module>) -> ModuleString {
+		ModuleString::from(modules.as_slice())
+	}
+}
+
+impl FromIterator<Module> for ModuleString {
+	fn from_iter<I: IntoIterator<Item = Module>>(iter: I) -> ModuleString {
+		let mut module_string = ModuleString::new();
+
+		for module in iter {
+			module_string.push(module.view());
+		}
+
+		module_string
+	}
+}
+
+impl Display for ModuleString {
+	fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+		write!(f, "{}", AnnotatedDisplay { value: self, annotations: None })
+	}
+}
@@ -15,8 +15,12 @@ pub struct WeightedChoice<'a, T:'a> {
 }
 
 impl<'a, T: Clone> WeightedChoice<'a, T> {
-    pub fn new(items: &'a mut [Weighted<T>]) -> WeightedChoice<'a, T> {
-        assert!(!items.is_empty(), "WeightedChoice::new called with no items");
+    /// Create a weighted choice over given items. Returns `None` if there are no items,
+    /// or if their weights do not sum up to a positive total.
+    pub fn new(items: &'a mut [Weighted<T>]) -> Option<WeightedChoice<'a, T>> {
+        if items.is_empty() {
+            return None;
+        }
 
         let mut running_total: f64 = 0.0;
 
@@ -24,14 +28,17 @@ impl<'a, T: Clone> WeightedChoice<'a, T> {
             running_total += item.weight;
             item.weight = running_total;
         }
-        assert!(running_total != 0.0, "WeightedChoice::new called with a total weight of 0");
 
-        WeightedChoice {
+        if running_total <= 0.0 || !running_total.is_finite() {
+            return None;
+        }
+
+        Some(WeightedChoice {
             items,
             // we're likely to be generating numbers in this range
             // relatively often, so might as well cache it
             weight_range: Uniform::new(0.0, running_total)
-        }
+        })
     }
 }
 
@@ -51,7 +58,6 @@ impl<'a, T: Clone> Distribution<T> for WeightedChoice<'a, T> {
             if self.items[i].weight <= sample_weight {
                 idx = i;
                 modifier += 1;
-            } else {
             }
             modifier /= 2;
         }
@@ -0,0 +1,350 @@
+use std::collections::*;
+use std::fmt::*;
+use crate::iteration::*;
+use crate::interpretation::*;
+use crate::module_string::*;
+
+/// How severe a problem found by the rule set analysis is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+	/// The system will fail when being iterated or interpreted.
+	Error,
+	/// The system works, but most likely not as intended.
+	Warning
+}
+
+/// The reason why a rule can never be applied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnreachableReason {
+	/// No module matching one of the signatures in the pattern is ever produced, neither by the axiom
+	/// nor by any right side.
+	NeverProduced,
+	/// The condition of the rule is constant false.
+	ConditionFalse,
+	/// The rule is shadowed by the given deterministic rule, which always matches first.
+	ShadowedBy(usize)
+}
+
+/// A problem found by the static analysis of an L-System. Rules are referred to by their index
+/// in the rule list of the iteration engine. A rule of `None` refers to the axiom.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Diagnostic {
+	/// The condition or the right side of a rule references a parameter that is not bound
+	/// by the rule pattern.
+	UnboundParameter { rule: usize, parameter: char },
+	/// The pattern of a rule binds the same parameter name more than once.
+	DuplicateBinding { rule: usize, parameter: char },
+	/// A module is produced with a parameter count that none of the patterns matching its identifier expect.
+	ArityMismatch { rule: Option<usize>, identifier: char, produced: usize, expected: Vec<usize> },
+	/// A rule can never be applied.
+	UnreachableRule { rule: usize, reason: UnreachableReason },
+	/// A module identifier that can appear in the module string has no interpretation associated with it.
+	/// Symbols that are intentionally not drawn can be associated with `TurtleCommand::Ignore`.
+	MissingInterpretation { identifier: char },
+	/// A module is produced with more parameters than its interpretation accepts.
+	InterpretationArityMismatch { rule: Option<usize>, identifier: char, produced: usize, accepted: usize }
+}
+
+impl Diagnostic {
+	/// Retrieve the severity of this diagnostic.
+	pub fn severity(&self) -> Severity {
+		match self {
+			Diagnostic::UnboundParameter{..} |
+			Diagnostic::DuplicateBinding{..} |
+			Diagnostic::InterpretationArityMismatch{..} => Severity::Error,
+			_ => Severity::Warning
+		}
+	}
+
+	/// Check whether this diagnostic describes a problem that will cause a failure.
+	pub fn is_error(&self) -> bool {
+		self.severity() == Severity::Error
+	}
+}
+
+fn write_origin(f: &mut Formatter<'_>, rule: &Option<usize>) -> Result {
+	match rule {
+		Some(r) => write!(f, "rule {}", r),
+		None => write!(f, "axiom")
+	}
+}
+
+impl Display for Diagnostic {
+	fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+		match self {
+			Diagnostic::UnboundParameter{rule, parameter} =>
+				write!(f, "rule {}: parameter '{}' is used but not bound by the pattern", rule, parameter),
+			Diagnostic::DuplicateBinding{rule, parameter} =>
+				write!(f, "rule {}: parameter '{}' is bound more than once in the pattern", rule, parameter),
+			Diagnostic::ArityMismatch{rule, identifier, produced, expected} => {
+				write_origin(f, rule)?;
+				write!(f, ": module '{}' is produced with {} parameter(s), but patterns expect {:?}", identifier, produced, expected)
+			},
+			Diagnostic::UnreachableRule{rule, reason} => match reason {
+				UnreachableReason::NeverProduced => write!(f, "rule {}: pattern never matches any produced module", rule),
+				UnreachableReason::ConditionFalse => write!(f, "rule {}: condition is always false", rule),
+				UnreachableReason::ShadowedBy(other) => write!(f, "rule {}: always shadowed by rule {}", rule, other)
+			},
+			Diagnostic::MissingInterpretation{identifier} =>
+				write!(f, "module '{}' has no interpretation", identifier),
+			Diagnostic::InterpretationArityMismatch{rule, identifier, produced, accepted} => {
+				write_origin(f, rule)?;
+				write!(f, ": module '{}' is produced with {} parameter(s), but its interpretation accepts at most {}", identifier, produced, accepted)
+			}
+		}
+	}
+}
+
+/// A module that can appear in a module string, together with its origin.
+struct ProducedModule {
+	origin: Option<usize>,
+	identifier: char,
+	annotation: Option<ModuleAnnotation>,
+	parameter_count: usize
+}
+
+/// All modules that can appear in a module string, in order of appearance.
+fn produced_modules(axiom: &ModuleString, rules: &[Rule]) -> Vec<ProducedModule> {
+	let mut modules: Vec<ProducedModule> = axiom.iter()
+		.map(|m| ProducedModule{ origin: None, identifier: m.identifier, annotation: m.annotation, parameter_count: m.parameter_count() })
+		.collect();
+
+	for (i, rule) in rules.iter().enumerate() {
+		for template in &rule.right_side {
+			modules.push(ProducedModule{
+				origin: Some(i),
+				identifier: template.identifier,
+				annotation: template.annotation,
+				parameter_count: template.parameter_count()
+			});
+		}
+	}
+
+	modules
+}
+
+/// All signatures of given pattern, each with a flag stating whether it is the center signature.
+fn signatures(pattern: &ModulePattern) -> impl Iterator<Item = (&ModuleSignature, bool)> {
+	pattern.match_left.iter().map(|s| (s, false))
+		.chain(std::iter::once((&pattern.match_center, true)))
+		.chain(pattern.match_right.iter().map(|s| (s, false)))
+}
+
+/// Check whether given signature can match modules with given identifier and annotation, regardless of their
+/// parameter count. Like `ModulePattern::fits`, this ignores the annotation of the center signature.
+fn can_match(signature: &ModuleSignature, center: bool, identifier: char, annotation: Option<ModuleAnnotation>) -> bool {
+	signature.identifier == identifier && (center || signature.annotation == annotation)
+}
+
+/// Check whether given rule always applies to every module matching its center signature.
+fn is_unconditional(rule: &Rule) -> bool {
+	rule.is_deterministic()
+		&& rule.pattern.match_left.is_none()
+		&& rule.pattern.match_right.is_none()
+		&& rule.pattern.condition == BooleanExpression::Const(true)
+}
+
+/// Check whether the center signatures of both rules match the same modules.
+fn same_center(a: &Rule, b: &Rule) -> bool {
+	let (a, b) = (&a.pattern.match_center, &b.pattern.match_center);
+	a.identifier == b.identifier && a.parameter_count() == b.parameter_count()
+}
+
+fn check_bindings(index: usize, rule: &Rule, diagnostics: &mut Vec<Diagnostic>) {
+	let mut bound = HashSet::new();
+	let mut reported = HashSet::new();
+
+	for (signature, _) in signatures(&rule.pattern) {
+		for p in &signature.parameters {
+			if !bound.insert(*p) && reported.insert(*p) {
+				diagnostics.push(Diagnostic::DuplicateBinding{ rule: index, parameter: *p });
+			}
+		}
+	}
+
+	let mut unbound = Vec::new();
+	let mut check = |p: char| {
+		if !bound.contains(&p) && !unbound.contains(&p) {
+			unbound.push(p);
+		}
+	};
+
+	rule.pattern.condition.visit_parameters(&mut check);
+
+	for template in &rule.right_side {
+		for expr in &template.parameter_expressions {
+			expr.visit_parameters(&mut check);
+		}
+	}
+
+	for p in unbound {
+		diagnostics.push(Diagnostic::UnboundParameter{ rule: index, parameter: p });
+	}
+}
+
+/// Perform static analysis of given axiom and rule set. This reports unbound parameters and duplicate bindings,
+/// which would cause iteration to fail, as well as arity mismatches and unreachable rules.
+pub fn validate_rules(axiom: &ModuleString, rules: &[Rule]) -> Vec<Diagnostic> {
+	let mut diagnostics = Vec::new();
+
+	for (i, rule) in rules.iter().enumerate() {
+		check_bindings(i, rule, &mut diagnostics);
+	}
+
+	let produced = produced_modules(axiom, rules);
+	let mut reported = HashSet::new();
+
+	for module in &produced {
+		// Parameter counts expected by all signatures that can match the module
+		let expected: BTreeSet<usize> = rules.iter()
+			.flat_map(|rule| signatures(&rule.pattern))
+			.filter(|(s, center)| can_match(s, *center, module.identifier, module.annotation))
+			.map(|(s, _)| s.parameter_count())
+			.collect();
+
+		if !expected.is_empty() && !expected.contains(&module.parameter_count)
+			&& reported.insert((module.identifier, module.annotation, module.parameter_count)) {
+			diagnostics.push(Diagnostic::ArityMismatch{
+				rule: module.origin,
+				identifier: module.identifier,
+				produced: module.parameter_count,
+				expected: expected.into_iter().collect()
+			});
+		}
+	}
+
+	for (i, rule) in rules.iter().enumerate() {
+		let reachable = signatures(&rule.pattern).all(|(s, center)| {
+			produced.iter().any(|m| can_match(s, center, m.identifier, m.annotation) && m.parameter_count == s.parameter_count())
+		});
+
+		let reason = if !reachable {
+			Some(UnreachableReason::NeverProduced)
+		} else if rule.pattern.condition == BooleanExpression::Const(false) {
+			Some(UnreachableReason::ConditionFalse)
+		} else {
+			// Deterministic rules always take precedence over stochastic ones, and the first
+			// matching deterministic rule is always the one that is applied.
+			rules.iter().enumerate()
+				.find(|(j, other)| {
+					*j != i && is_unconditional(other) && same_center(rule, other)
+						&& (!rule.is_deterministic() || *j < i)
+				})
+				.map(|(j, _)| UnreachableReason::ShadowedBy(j))
+		};
+
+		if let Some(reason) = reason {
+			diagnostics.push(Diagnostic::UnreachableRule{ rule: i, reason });
+		}
+	}
+
+	diagnostics
+}
+
+/// Check given axiom and rule set against the associations stored in given interpretation engine.
+/// This reports symbols without interpretation as well as modules that carry more parameters than their
+/// interpretation can consume.
+pub fn validate_interpretation(axiom: &ModuleString, rules: &[Rule], interpretation: &InterpretationEngine) -> Vec<Diagnostic> {
+	let mut diagnostics = Vec::new();
+	let mut missing = BTreeSet::new();
+	let mut reported = HashSet::new();
+
+	for module in produced_modules(axiom, rules) {
+		match interpretation.max_parameters(module.identifier, module.annotation) {
+			None => {
+				missing.insert(module.identifier);
+			},
+			Some(accepted) => {
+				if module.parameter_count > accepted && reported.insert((module.identifier, module.annotation, module.parameter_count)) {
+					diagnostics.push(Diagnostic::InterpretationArityMismatch{
+						rule: module.origin,
+						identifier: module.identifier,
+						produced: module.parameter_count,
+						accepted
+					});
+				}
+			}
+		}
+	}
+
+	for identifier in missing {
+		diagnostics.push(Diagnostic::MissingInterpretation{ identifier });
+	}
+
+	diagnostics
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::annotation::AnnotationRegistry;
+	use crate::drawing::TurtleCommand;
+	use crate::grammar::lsystem_parser;
+
+	fn validate(axiom: &str, rules: &str) -> Vec<Diagnostic> {
+		let annotations = AnnotationRegistry::new();
+		let axiom = ModuleString::from(lsystem_parser::module_string(axiom, &annotations).unwrap());
+		validate_rules(&axiom, &lsystem_parser::rule_list(rules, &annotations).unwrap())
+	}
+
+	#[test]
+	fn reports_unbound_parameters_and_duplicate_bindings() {
+		assert_eq!(validate("A(1)", "A(x) : y > 0 -> A(x+z)"), vec![
+			Diagnostic::UnboundParameter{ rule: 0, parameter: 'y' },
+			Diagnostic::UnboundParameter{ rule: 0, parameter: 'z' }
+		]);
+
+		assert_eq!(validate("B(1)A(1)", "B(x) < A(x) -> A(x)"), vec![
+			Diagnostic::DuplicateBinding{ rule: 0, parameter: 'x' }
+		]);
+	}
+
+	#[test]
+	fn reports_arity_mismatches() {
+		assert_eq!(validate("A(1)", "A(x) -> A(x,1)"), vec![
+			Diagnostic::ArityMismatch{ rule: Some(0), identifier: 'A', produced: 2, expected: vec![1] }
+		]);
+	}
+
+	#[test]
+	fn reports_unreachable_rules() {
+		assert_eq!(validate("A", "B -> A"), vec![
+			Diagnostic::UnreachableRule{ rule: 0, reason: UnreachableReason::NeverProduced }
+		]);
+
+		assert_eq!(validate("A(1)", "A(x) : x > 0 && false -> A(x)\nA(x) : false -> A(x)"), vec![
+			Diagnostic::UnreachableRule{ rule: 1, reason: UnreachableReason::ConditionFalse }
+		]);
+
+		assert_eq!(validate("A", "A -> AB\nA : 0.5 -> B"), vec![
+			Diagnostic::UnreachableRule{ rule: 1, reason: UnreachableReason::ShadowedBy(0) }
+		]);
+	}
+
+	#[test]
+	fn center_annotation_is_ignored_like_when_matching() {
+		// `~A -> F` rewrites every `A`, which is how the iteration engine matches the center of a pattern
+		assert!(validate("A", "~A -> F").is_empty());
+		assert_eq!(validate("A", "A -> F\n~A -> B"), vec![
+			Diagnostic::UnreachableRule{ rule: 1, reason: UnreachableReason::ShadowedBy(0) }
+		]);
+
+		// Side signatures only match modules carrying the same annotation
+		assert_eq!(validate("BA", "~B < A -> F"), vec![
+			Diagnostic::UnreachableRule{ rule: 0, reason: UnreachableReason::NeverProduced }
+		]);
+	}
+
+	#[test]
+	fn reports_interpretation_problems() {
+		let annotations = AnnotationRegistry::new();
+		let axiom = ModuleString::from(lsystem_parser::module_string("F(1,2,3,4)X", &annotations).unwrap());
+		let mut interpretation = InterpretationEngine::new();
+		interpretation.associate('F', TurtleCommand::Forward);
+
+		assert_eq!(validate_interpretation(&axiom, &[], &interpretation), vec![
+			Diagnostic::InterpretationArityMismatch{ rule: None, identifier: 'F', produced: 4, accepted: 3 },
+			Diagnostic::MissingInterpretation{ identifier: 'X' }
+		]);
+	}
+}